@@ -0,0 +1,678 @@
+/*  Contains the command line interface.
+ *
+ *  `gamma_daemon run` starts the daemon, the other subcommands talk to a daemon that is already
+ *  running over its control socket (see daemon::control), or check a config file.
+ *
+ *  For older setups, `gamma_daemon` on its own and `gamma_daemon <config path>` still run the
+ *  daemon.
+ *
+ * */
+
+use crate::daemon::config::{self, Config, ConfigError, Level};
+use crate::daemon::control::{self, Request, Response, Status};
+use crate::daemon::sysfs::Sysfs;
+use crate::daemon::trace::{self, Decision};
+use crate::daemon::{self, Backlight};
+use crate::error::{Error, EXIT_CONFIG};
+use std::path::Path;
+
+pub const USAGE: &str = "Usage: gamma_daemon [COMMAND] [--json]
+
+Commands:
+  run [--foreground] [--record TRACE] [CONFIG]
+                         Run the daemon, this is the default
+  status                 Show what the running daemon is doing
+  set LEVEL              Set every backlight to LEVEL, i.e: 120 or 60%
+  pause                  Stop the running daemon from changing the brightness
+  resume                 Let the running daemon change the brightness again
+  reload                 Make the running daemon read its config file again
+  quit                   Stop the running daemon
+  check-config [CONFIG]  Check a config file for errors
+  print-config [--defaults] [CONFIG]
+                         Print the config the daemon would run with, every value
+                         left out of CONFIG filled in with its default
+  simulate TRACE [CONFIG]
+                         Replay a trace recorded with --record, and print the
+                         brightness changes the daemon would make with CONFIG
+  install [PREFIX]       Print the udev rules and systemd units for this machine,
+                         or write them under PREFIX
+
+CONFIG is read on top of $XDG_CONFIG_HOME/GammaDaemon/conf.toml,
+$HOME/.config/GammaDaemon/conf.toml, $XDG_CONFIG_DIRS/GammaDaemon/conf.toml
+and /etc/gamma_daemon/conf.toml, whichever of them exist
+
+Options:
+  -f, --foreground       Run without forking, and log to stderr
+  --record TRACE         Add the battery state and AC of every loop to TRACE
+  --defaults             Print the default config, without reading CONFIG
+  --sysfs-root DIR       Read the backlights, batteries and AC from DIR instead of
+                         /sys, also set with $GAMMA_DAEMON_SYSFS_ROOT
+  --json                 Print the daemon's replies as JSON
+  -h, --help             Print this help";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run {
+        config: Option<String>,
+        foreground: bool,
+        // the trace to record to
+        record: Option<String>,
+    },
+    // a request for the running daemon
+    Send(Request),
+    CheckConfig {
+        config: Option<String>,
+    },
+    PrintConfig {
+        config: Option<String>,
+        // ignore the file and print the defaults
+        defaults: bool,
+    },
+    Simulate {
+        trace: String,
+        config: Option<String>,
+    },
+    Install {
+        prefix: Option<String>,
+    },
+    Help,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Cli {
+    pub command: Command,
+    pub json: bool,
+    // see daemon::sysfs::Sysfs::from_args
+    pub sysfs_root: Option<String>,
+}
+
+/* Parses the command line arguments, without the program name.
+ * Returns a message saying what's wrong if they don't make sense.
+ * */
+pub fn parse_args(args: &[String]) -> Result<Cli, String> {
+    let mut json = false;
+    let mut foreground = false;
+    let mut defaults = false;
+    let mut sysfs_root = None;
+    let mut record = None;
+    let mut words = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sysfs-root" => match args.next() {
+                Some(root) => sysfs_root = Some(root.to_string()),
+                None => return Err("--sysfs-root needs a directory".to_string()),
+            },
+            flag if flag.starts_with("--sysfs-root=") => {
+                sysfs_root = Some(flag["--sysfs-root=".len()..].to_string())
+            }
+            "--record" => match args.next() {
+                Some(trace) => record = Some(trace.to_string()),
+                None => return Err("--record needs a file".to_string()),
+            },
+            flag if flag.starts_with("--record=") => {
+                record = Some(flag["--record=".len()..].to_string())
+            }
+            "--json" => json = true,
+            "-f" | "--foreground" => foreground = true,
+            "--defaults" => defaults = true,
+            "-h" | "--help" => words.insert(0, "help"),
+            flag if flag.starts_with('-') => return Err(format!("unknown option {}", flag)),
+            word => words.push(word),
+        }
+    }
+
+    let (command, rest) = match words.split_first() {
+        Some((command, rest)) => (*command, rest),
+        None => ("run", &[][..]),
+    };
+
+    // the config path or prefix, for the commands that take one
+    let config = match rest {
+        [] => Ok(None),
+        [path] => Ok(Some(path.to_string())),
+        _ => Err(format!("too many arguments for {}", command)),
+    };
+    // the commands that take nothing
+    let send = |request: Request| match rest {
+        [] => Ok(Command::Send(request)),
+        _ => Err(format!("{} doesn't take any arguments", command)),
+    };
+
+    let command = match command {
+        "help" => Command::Help,
+        "run" => Command::Run {
+            config: config?,
+            foreground,
+            record: record.clone(),
+        },
+        "check-config" => Command::CheckConfig { config: config? },
+        "print-config" => Command::PrintConfig {
+            config: config?,
+            defaults,
+        },
+        "simulate" => match rest {
+            [trace] => Command::Simulate {
+                trace: trace.to_string(),
+                config: None,
+            },
+            [trace, path] => Command::Simulate {
+                trace: trace.to_string(),
+                config: Some(path.to_string()),
+            },
+            [] => return Err("simulate needs a trace, see run --record".to_string()),
+            _ => return Err("too many arguments for simulate".to_string()),
+        },
+        "install" => Command::Install { prefix: config? },
+        "status" => send(Request::Status)?,
+        "pause" => send(Request::Pause)?,
+        "resume" => send(Request::Resume)?,
+        "reload" => send(Request::Reload)?,
+        "quit" => send(Request::Quit)?,
+        "set" => match rest {
+            [level] => Command::Send(Request::Set {
+                level: level.parse::<Level>()?,
+            }),
+            [] => return Err("set needs a brightness, i.e: 120 or 60%".to_string()),
+            _ => return Err("too many arguments for set".to_string()),
+        },
+        // older versions took the config path as the only argument
+        path if rest.is_empty() && (path.contains('/') || path.ends_with(".toml")) => {
+            Command::Run {
+                config: Some(path.to_string()),
+                foreground,
+                record: record.clone(),
+            }
+        }
+        other => return Err(format!("unknown command {}", other)),
+    };
+    if foreground && !matches!(command, Command::Run { .. }) {
+        return Err("--foreground only works with run".to_string());
+    }
+    if record.is_some() && !matches!(command, Command::Run { .. }) {
+        return Err("--record only works with run".to_string());
+    }
+    if defaults && !matches!(command, Command::PrintConfig { .. }) {
+        return Err("--defaults only works with print-config".to_string());
+    }
+
+    Ok(Cli {
+        command,
+        json,
+        sysfs_root,
+    })
+}
+
+/* Sends request to the running daemon and prints its reply.
+ * Returns the exit code, non-zero if the daemon couldn't be reached or the request failed.
+ * */
+pub fn send(request: Request, json: bool) -> i32 {
+    let response = match control::send(&request) {
+        Ok(response) => response,
+        Err(e) => {
+            let e = Error::Ipc(e);
+            eprintln!("{}", e);
+            return e.exit_code();
+        }
+    };
+
+    print_response(&request, &response, json);
+    // the daemon is there, it just couldn't do what we asked
+    match response.ok {
+        true => 0,
+        false => 1,
+    }
+}
+
+/* Says whether the config the daemon would run with is valid, for the backlights on this machine
+ * as well as on its own. That is path layered over the files the daemon reads (see
+ * config::search_paths), like it is for run, and which files were merged is printed.
+ * A missing path, or no file at all without one, is an error here, since there is nothing to check.
+ * Returns the exit code, non-zero if it isn't.
+ * */
+pub fn check_config(path: Option<String>, sysfs: &Sysfs, json: bool) -> i32 {
+    let loaded = config::load_layered(
+        &config::search_paths(path.as_deref()),
+        path.as_deref().map(Path::new),
+    );
+    let (name, loaded) = match loaded {
+        Ok(loaded) if loaded.files.is_empty() => {
+            eprintln!("No config file found, gamma_daemon would use the default config");
+            return EXIT_CONFIG;
+        }
+        Ok(loaded) => (config::describe_files(&loaded.files), Ok(loaded.config)),
+        Err(e) => (String::new(), Err(e)),
+    };
+    if !json && !name.is_empty() {
+        eprintln!("# config from {}", name);
+    }
+
+    let checked = loaded.and_then(|config| {
+        check_backlights(&config, sysfs).map_err(|source| Error::Config {
+            path: name.clone(),
+            source,
+        })
+    });
+    let response = match checked {
+        Ok(_) => Response::ok(),
+        Err(e) => Response::error(e.to_string()),
+    };
+
+    if json {
+        println!("{}", serde_json::to_string(&response).unwrap_or_default());
+    } else {
+        match &response.error {
+            None => println!("The config is valid"),
+            Some(e) => eprintln!("{}", e),
+        }
+    }
+
+    match response.ok {
+        true => 0,
+        false => EXIT_CONFIG,
+    }
+}
+
+/* Prints the config the daemon would run with as toml, or JSON with --json, with every value the
+ * files leave out filled in. path is layered over the other files, like it is for run. With
+ * defaults, the file isn't read and only the defaults are printed.
+ * Returns the exit code, non-zero if the file has an error.
+ * */
+pub fn print_config(path: Option<String>, defaults: bool, json: bool) -> i32 {
+    let config = match defaults {
+        true => Config::default(),
+        false => match config::load_layered(
+            &config::search_paths(path.as_deref()),
+            path.as_deref().map(Path::new),
+        ) {
+            Ok(loaded) => {
+                eprintln!("# from {}", config::describe_files(&loaded.files));
+                loaded.config
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                return EXIT_CONFIG;
+            }
+        },
+    };
+
+    let text = match json {
+        true => serde_json::to_string_pretty(&config).map_err(|e| e.to_string()),
+        false => toml::to_string(&config).map_err(|e| e.to_string()),
+    };
+    match text {
+        Ok(text) => {
+            println!("{}", text.trim_end());
+            0
+        }
+        Err(e) => {
+            eprintln!("Can't print the config: {}", e);
+            1
+        }
+    }
+}
+
+/* Replays the trace at path through the config the daemon would run with, config being layered
+ * over the other files like it is for run, and prints the brightness changes the daemon would
+ * have made. The backlights in sysfs are only read for their names and max_brightness, and if
+ * there are none, one called "backlight" with a max_brightness of 255 is made up.
+ * Returns the exit code, non-zero if the trace or config can't be read.
+ * */
+pub fn simulate(path: &str, config: Option<String>, sysfs: &Sysfs, json: bool) -> i32 {
+    let samples = match trace::read_trace(Path::new(path)) {
+        Ok(samples) => samples,
+        Err(source) => {
+            let e = Error::Trace {
+                context: format!("Can't read {}", path),
+                source,
+            };
+            eprintln!("{}", e);
+            return e.exit_code();
+        }
+    };
+    let loaded = match config::load_layered(
+        &config::search_paths(config.as_deref()),
+        config.as_deref().map(Path::new),
+    ) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_CONFIG;
+        }
+    };
+    eprintln!("# config from {}", config::describe_files(&loaded.files));
+
+    let devices: Vec<(String, u32)> = match sysfs.backlights() {
+        Ok(devices) if !devices.is_empty() => devices
+            .iter()
+            .map(|d| (d.name().to_string(), d.max_brightness()))
+            .collect(),
+        _ => {
+            eprintln!("# no backlights found, simulating one called backlight with a max_brightness of 255");
+            vec![("backlight".to_string(), 255)]
+        }
+    };
+    let problems: Vec<String> = devices
+        .iter()
+        .flat_map(|(name, max)| loaded.config.check_device(name, *max))
+        .collect();
+    if !problems.is_empty() {
+        let e = Error::Config {
+            path: config::describe_files(&loaded.files),
+            source: ConfigError::Invalid(problems),
+        };
+        eprintln!("{}", e);
+        return e.exit_code();
+    }
+
+    let decisions =
+        daemon::with_log_on_stderr(|| trace::simulate(&samples, &loaded.config, &devices));
+    for decision in decisions {
+        match json {
+            true => println!("{}", serde_json::to_string(&decision).unwrap_or_default()),
+            false => println!("{}", format_decision(&decision)),
+        }
+    }
+    0
+}
+
+// i.e: +0:30:00 discharging, 19%, unplugged: intel_backlight 50
+fn format_decision(decision: &Decision) -> String {
+    let seconds = decision.time_ms / 1000;
+    let devices: Vec<String> = decision
+        .devices
+        .iter()
+        .map(|d| format!("{} {}", d.name, d.brightness))
+        .collect();
+
+    format!(
+        "+{}:{:02}:{:02} {}, {:.0}%, {}: {}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        decision.state,
+        decision.soc * 100.0,
+        match decision.ac {
+            true => "plugged in",
+            false => "unplugged",
+        },
+        devices.join(", ")
+    )
+}
+
+// checks the raw brightness values fit every backlight that can be found
+fn check_backlights(config: &config::Config, sysfs: &Sysfs) -> Result<(), ConfigError> {
+    let devices = match sysfs.backlights() {
+        Ok(devices) => devices,
+        Err(e) => {
+            eprintln!(
+                "Can't read the backlight devices ({}), skipping the brightness checks",
+                e
+            );
+            return Ok(());
+        }
+    };
+
+    let problems: Vec<String> = devices
+        .iter()
+        .flat_map(|d| config.check_device(d.name(), d.max_brightness()))
+        .collect();
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(ConfigError::Invalid(problems)),
+    }
+}
+
+fn print_response(request: &Request, response: &Response, json: bool) {
+    if json {
+        println!("{}", serde_json::to_string(response).unwrap_or_default());
+        return;
+    }
+
+    if let Some(e) = &response.error {
+        eprintln!("gamma_daemon: {}", e);
+        return;
+    }
+
+    match (request, &response.status) {
+        (_, Some(status)) => print!("{}", format_status(status)),
+        (Request::Set { level }, _) => println!("Brightness set to {}", level),
+        (Request::Pause, _) => println!("Paused"),
+        (Request::Resume, _) => println!("Resumed"),
+        (Request::Reload, _) => println!("Config reloaded"),
+        (Request::Quit, _) => println!("gamma_daemon stopped"),
+        (Request::Status, None) => {}
+    }
+}
+
+fn format_status(status: &Status) -> String {
+    let mut text = format!(
+        "Battery: {}, {:.0}%\nAC: {}\nPaused: {}\nConfig: {}\n",
+        status.battery_state,
+        status.state_of_charge * 100.0,
+        match status.ac_online {
+            true => "plugged in",
+            false => "unplugged",
+        },
+        match status.paused {
+            true => "yes",
+            false => "no",
+        },
+        match status.config_files.is_empty() {
+            true => "defaults".to_string(),
+            false => status.config_files.join(" + "),
+        },
+    );
+
+    for device in &status.devices {
+        let brightness = match device.brightness {
+            Some(b) => b.to_string(),
+            None => "?".to_string(),
+        };
+        text += &format!(
+            "{}: {}/{}{}\n",
+            device.name,
+            brightness,
+            device.max_brightness,
+            match device.overridden {
+                true => " (set by hand)",
+                false => "",
+            }
+        );
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::control::DeviceStatus;
+
+    fn parse(args: &[&str]) -> Result<Cli, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        parse_args(&args)
+    }
+
+    fn command(args: &[&str]) -> Command {
+        parse(args).unwrap().command
+    }
+
+    #[test]
+    fn test_run_commands() {
+        let daemon = Command::Run {
+            config: None,
+            foreground: false,
+            record: None,
+        };
+        assert_eq!(command(&[]), daemon);
+        assert_eq!(command(&["run"]), daemon);
+        assert_eq!(
+            command(&["run", "conf.toml"]),
+            Command::Run {
+                config: Some("conf.toml".to_string()),
+                foreground: false,
+                record: None,
+            }
+        );
+        // the old way of passing the config path
+        assert_eq!(
+            command(&["/home/me/gamma.toml"]),
+            Command::Run {
+                config: Some("/home/me/gamma.toml".to_string()),
+                foreground: false,
+                record: None,
+            }
+        );
+        assert!(parse(&["run", "a.toml", "b.toml"]).is_err());
+
+        let foreground = Command::Run {
+            config: None,
+            foreground: true,
+            record: None,
+        };
+        assert_eq!(command(&["run", "--foreground"]), foreground);
+        assert_eq!(command(&["-f"]), foreground);
+        assert!(parse(&["status", "--foreground"]).is_err());
+
+        let record = Command::Run {
+            config: None,
+            foreground: false,
+            record: Some("trace.jsonl".to_string()),
+        };
+        assert_eq!(command(&["run", "--record", "trace.jsonl"]), record);
+        assert_eq!(command(&["--record=trace.jsonl"]), record);
+        assert!(parse(&["run", "--record"]).is_err());
+        assert!(parse(&["status", "--record", "trace.jsonl"]).is_err());
+    }
+
+    #[test]
+    fn test_simulate() {
+        assert_eq!(
+            command(&["simulate", "trace.jsonl"]),
+            Command::Simulate {
+                trace: "trace.jsonl".to_string(),
+                config: None,
+            }
+        );
+        assert_eq!(
+            command(&["simulate", "trace.jsonl", "conf.toml"]),
+            Command::Simulate {
+                trace: "trace.jsonl".to_string(),
+                config: Some("conf.toml".to_string()),
+            }
+        );
+        assert!(parse(&["simulate"]).is_err());
+        assert!(parse(&["simulate", "a", "b", "c"]).is_err());
+
+        let decision = Decision {
+            time_ms: 5_430_000,
+            state: battery::State::Discharging,
+            soc: 0.19,
+            ac: false,
+            devices: vec![
+                trace::DeviceDecision {
+                    name: "intel_backlight".to_string(),
+                    brightness: 50,
+                },
+                trace::DeviceDecision {
+                    name: "acpi_video0".to_string(),
+                    brightness: 4,
+                },
+            ],
+        };
+        assert_eq!(
+            format_decision(&decision),
+            "+1:30:30 discharging, 19%, unplugged: intel_backlight 50, acpi_video0 4"
+        );
+    }
+
+    #[test]
+    fn test_client_commands() {
+        assert_eq!(command(&["status"]), Command::Send(Request::Status));
+        assert_eq!(command(&["pause"]), Command::Send(Request::Pause));
+        assert_eq!(command(&["resume"]), Command::Send(Request::Resume));
+        assert_eq!(command(&["reload"]), Command::Send(Request::Reload));
+        assert_eq!(command(&["quit"]), Command::Send(Request::Quit));
+        assert_eq!(
+            command(&["set", "60%"]),
+            Command::Send(Request::Set {
+                level: Level::Percent(60.0)
+            })
+        );
+        assert_eq!(
+            command(&["check-config"]),
+            Command::CheckConfig { config: None }
+        );
+        assert_eq!(
+            command(&["print-config", "conf.toml"]),
+            Command::PrintConfig {
+                config: Some("conf.toml".to_string()),
+                defaults: false,
+            }
+        );
+        assert_eq!(
+            command(&["print-config", "--defaults"]),
+            Command::PrintConfig {
+                config: None,
+                defaults: true,
+            }
+        );
+        assert_eq!(
+            command(&["install", "/"]),
+            Command::Install {
+                prefix: Some("/".to_string())
+            }
+        );
+
+        let cli = parse(&["status", "--json"]).unwrap();
+        assert!(cli.json);
+        assert!(!parse(&["status"]).unwrap().json);
+        assert_eq!(command(&["status", "--help"]), Command::Help);
+    }
+
+    #[test]
+    fn test_bad_args() {
+        assert!(parse(&["stauts"]).is_err());
+        assert!(parse(&["set"]).is_err());
+        assert!(parse(&["set", "bright"]).is_err());
+        assert!(parse(&["set", "150%"]).is_err());
+        assert!(parse(&["pause", "now"]).is_err());
+        assert!(parse(&["status", "--verbose"]).is_err());
+        assert!(parse(&["run", "--defaults"]).is_err());
+    }
+
+    #[test]
+    fn test_format_status() {
+        let status = Status {
+            paused: false,
+            battery_state: "discharging".to_string(),
+            state_of_charge: 0.754,
+            ac_online: false,
+            config_files: vec![
+                "/etc/gamma_daemon/conf.toml".to_string(),
+                "conf.toml".to_string(),
+            ],
+            devices: vec![
+                DeviceStatus {
+                    name: "intel_backlight".to_string(),
+                    brightness: Some(155),
+                    max_brightness: 255,
+                    overridden: false,
+                },
+                DeviceStatus {
+                    name: "acpi_video0".to_string(),
+                    brightness: None,
+                    max_brightness: 15,
+                    overridden: true,
+                },
+            ],
+        };
+
+        assert_eq!(
+            format_status(&status),
+            "Battery: discharging, 75%\nAC: unplugged\nPaused: no\nConfig: /etc/gamma_daemon/conf.toml + conf.toml\nintel_backlight: 155/255\nacpi_video0: ?/15 (set by hand)\n"
+        );
+    }
+}
@@ -0,0 +1,396 @@
+/*  Contains the Batteries struct, which bundles every battery in the notebook so the daemon can
+ *  treat them as one.
+ *
+ *  A machine without batteries (i.e: a desktop) gets an empty Batteries, which reports an
+ *  Unknown state and a full charge, so the daemon can carry on as if it is always on AC.
+ *
+ *  Notebooks with more than one battery (i.e: ThinkPads with an internal and an external battery)
+ *  report a misleading charge if we only look at the first one, so by default the charge is
+ *  weighted by how much energy each battery holds, and the state is combined from all of them.
+ *  The config can also pick a single battery by model or serial number instead.
+ *
+ * */
+
+use crate::daemon::config::BatterySelection;
+use crate::daemon::BatterySource;
+use battery::{Battery, Manager, State};
+use std::io;
+
+/* What we need from one battery, whichever source it comes from */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reading {
+    pub model: Option<String>,
+    pub serial_number: Option<String>,
+    pub state: State,
+    // any unit, as long as energy_full uses the same one
+    pub energy: f32,
+    pub energy_full: f32,
+    // 0.0 to 1.0
+    pub state_of_charge: f32,
+}
+
+impl Reading {
+    // true if the battery's model or serial number is name
+    fn matches(&self, name: &str) -> bool {
+        self.model.as_deref().map(str::trim) == Some(name)
+            || self.serial_number.as_deref().map(str::trim) == Some(name)
+    }
+}
+
+/* The batteries the battery crate finds, which only knows the real /sys */
+pub struct CrateBatteries {
+    manager: Manager,
+    batteries: Vec<Battery>,
+}
+
+impl CrateBatteries {
+    pub fn new() -> Result<Self, battery::Error> {
+        let mut source = CrateBatteries {
+            manager: Manager::new()?,
+            batteries: Vec::new(),
+        };
+        source.scan()?;
+        Ok(source)
+    }
+}
+
+impl BatterySource for CrateBatteries {
+    fn scan(&mut self) -> Result<(), battery::Error> {
+        self.batteries = self.manager.batteries()?.collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    fn refresh(&mut self) -> Result<(), battery::Error> {
+        for battery in self.batteries.iter_mut() {
+            self.manager.refresh(battery)?;
+        }
+        Ok(())
+    }
+
+    fn readings(&self) -> Vec<Reading> {
+        self.batteries
+            .iter()
+            .map(|b| Reading {
+                model: b.model().map(str::to_string),
+                serial_number: b.serial_number().map(str::to_string),
+                state: b.state(),
+                energy: b.energy().value,
+                energy_full: b.energy_full().value,
+                state_of_charge: b.state_of_charge().value,
+            })
+            .collect()
+    }
+}
+
+pub struct Batteries<S> {
+    source: S,
+    selection: BatterySelection,
+    // every battery as of the last refresh that could read them
+    readings: Vec<Reading>,
+}
+
+impl<S: BatterySource> Batteries<S> {
+    /* Uses the batteries in source picked by selection */
+    pub fn new(source: S, selection: &BatterySelection) -> Self {
+        let mut batteries = Batteries {
+            readings: source.readings(),
+            source,
+            selection: BatterySelection::Aggregate,
+        };
+        batteries.select(selection);
+        batteries
+    }
+
+    /* Picks which batteries count, i.e: after the config changed.
+     *
+     * If a named battery can't be found, every battery is used instead, and a message is printed.
+     * */
+    pub fn select(&mut self, selection: &BatterySelection) {
+        if let BatterySelection::Named(name) = selection {
+            let readings = &self.readings;
+            if !readings.is_empty() && !readings.iter().any(|r| r.matches(name)) {
+                eprintln!(
+                    "No battery with model or serial number \"{}\", gamma_daemon will use every battery",
+                    name
+                );
+            }
+        }
+        self.selection = selection.clone();
+    }
+
+    /* Reads fresh values for every battery. While there are none, they are looked up again, so a
+     * battery that shows up after we started (i.e: one that is put in, or a driver that loads
+     * late) is picked up.
+     *
+     * If the batteries can't be read, the last values are kept and the error is returned.
+     * */
+    pub fn refresh(&mut self) -> Result<(), battery::Error> {
+        if self.readings.is_empty() {
+            self.source.scan()?;
+            self.source.refresh()?;
+        } else {
+            self.refresh_found()?;
+        }
+
+        self.readings = self.source.readings();
+        Ok(())
+    }
+
+    /* Refreshes the batteries we found before. If that fails, they are looked up again, since a
+     * battery that went away (or came back) is the usual reason. Finding none is an error too: a
+     * battery that is gone for a moment (i.e: while resuming) mustn't make this look like a
+     * machine without one, and the daemon decides how long to wait for it.
+     * */
+    fn refresh_found(&mut self) -> Result<(), battery::Error> {
+        let e = match self.source.refresh() {
+            Ok(_) if !self.source.readings().is_empty() => return Ok(()),
+            Ok(_) => io::Error::new(io::ErrorKind::NotFound, "the batteries went away").into(),
+            Err(e) => e,
+        };
+
+        if self.source.scan().is_err() || self.source.readings().is_empty() {
+            return Err(e);
+        }
+        self.source.refresh()
+    }
+
+    // true on machines without a battery
+    pub fn is_empty(&self) -> bool {
+        self.readings().is_empty()
+    }
+
+    pub fn state(&self) -> State {
+        let states: Vec<State> = self.readings().iter().map(|r| r.state).collect();
+        aggregate_state(&states)
+    }
+
+    pub fn state_of_charge(&self) -> f32 {
+        let readings = self.readings();
+        let energies: Vec<(f32, f32)> =
+            readings.iter().map(|r| (r.energy, r.energy_full)).collect();
+
+        match aggregate_soc(&energies) {
+            Some(soc) => soc,
+            None if readings.is_empty() => 1.0,
+            // no battery knows its energy, so fall back to the plain average
+            None => {
+                let total: f32 = readings.iter().map(|r| r.state_of_charge).sum();
+                total / readings.len() as f32
+            }
+        }
+    }
+
+    // the batteries that count, the selected one if it's there, otherwise every one
+    fn readings(&self) -> Vec<Reading> {
+        if let BatterySelection::Named(name) = &self.selection {
+            if let Some(reading) = self.readings.iter().find(|r| r.matches(name)) {
+                return vec![reading.clone()];
+            }
+        }
+        self.readings.clone()
+    }
+}
+
+/* Returns the state of several batteries combined.
+ *
+ * Any battery charging means we are charging, then any discharging means we are discharging.
+ * Otherwise the batteries are idle, so we are Empty if they all are, Full if any is full,
+ * and Unknown if we can't tell.
+ * */
+fn aggregate_state(states: &[State]) -> State {
+    let any = |state: State| states.contains(&state);
+
+    if any(State::Charging) {
+        State::Charging
+    } else if any(State::Discharging) {
+        State::Discharging
+    } else if !states.is_empty() && states.iter().all(|s| *s == State::Empty) {
+        State::Empty
+    } else if any(State::Full) {
+        State::Full
+    } else {
+        State::Unknown
+    }
+}
+
+/* Returns the state of charge of several batteries, given each one's (energy, energy_full).
+ * A battery holding more energy counts for more. Returns None if the total capacity is 0.
+ * */
+fn aggregate_soc(energies: &[(f32, f32)]) -> Option<f32> {
+    let energy: f32 = energies.iter().map(|e| e.0).sum();
+    let energy_full: f32 = energies.iter().map(|e| e.1).sum();
+
+    if energy_full <= 0.0 {
+        return None;
+    }
+    Some((energy / energy_full).clamp(0.0, 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregate_state() {
+        use State::*;
+
+        assert_eq!(aggregate_state(&[Discharging]), Discharging);
+        assert_eq!(aggregate_state(&[Full, Charging]), Charging);
+        assert_eq!(aggregate_state(&[Discharging, Charging]), Charging);
+        assert_eq!(aggregate_state(&[Unknown, Discharging]), Discharging);
+        assert_eq!(aggregate_state(&[Full, Full]), Full);
+        // internal battery full and idle while the external one waits
+        assert_eq!(aggregate_state(&[Full, Unknown]), Full);
+        assert_eq!(aggregate_state(&[Empty, Empty]), Empty);
+        assert_eq!(aggregate_state(&[Empty, Full]), Full);
+        assert_eq!(aggregate_state(&[Unknown, Unknown]), Unknown);
+        assert_eq!(aggregate_state(&[]), Unknown);
+    }
+
+    // fixed readings, that never fail to refresh
+    impl BatterySource for Vec<Reading> {
+        fn scan(&mut self) -> Result<(), battery::Error> {
+            Ok(())
+        }
+
+        fn refresh(&mut self) -> Result<(), battery::Error> {
+            Ok(())
+        }
+
+        fn readings(&self) -> Vec<Reading> {
+            self.clone()
+        }
+    }
+
+    fn reading(serial_number: &str, state: State, energy: f32, energy_full: f32) -> Reading {
+        Reading {
+            model: Some("5B10W13975".to_string()),
+            serial_number: Some(serial_number.to_string()),
+            state,
+            energy,
+            energy_full,
+            state_of_charge: energy / energy_full,
+        }
+    }
+
+    #[test]
+    fn test_no_batteries() {
+        let batteries = Batteries::new(Vec::new(), &BatterySelection::Aggregate);
+
+        assert!(batteries.is_empty());
+        assert_eq!(batteries.state(), State::Unknown);
+        assert_eq!(batteries.state_of_charge(), 1.0);
+    }
+
+    // batteries that are only there while plugged, and found again by a scan
+    struct Removable {
+        batteries: Vec<Reading>,
+        found: Vec<Reading>,
+        plugged: bool,
+    }
+
+    impl Removable {
+        fn new(batteries: Vec<Reading>, plugged: bool) -> Self {
+            Removable {
+                found: if plugged {
+                    batteries.clone()
+                } else {
+                    Vec::new()
+                },
+                batteries,
+                plugged,
+            }
+        }
+    }
+
+    impl BatterySource for Removable {
+        fn scan(&mut self) -> Result<(), battery::Error> {
+            self.found = match self.plugged {
+                true => self.batteries.clone(),
+                false => Vec::new(),
+            };
+            Ok(())
+        }
+
+        fn refresh(&mut self) -> Result<(), battery::Error> {
+            match self.plugged || self.found.is_empty() {
+                true => Ok(()),
+                false => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+            }
+        }
+
+        fn readings(&self) -> Vec<Reading> {
+            self.found.clone()
+        }
+    }
+
+    #[test]
+    fn test_late_battery() {
+        let source = Removable::new(vec![reading("1234", State::Discharging, 10.0, 40.0)], false);
+        let mut batteries = Batteries::new(source, &BatterySelection::Aggregate);
+        assert!(batteries.is_empty());
+        batteries.refresh().unwrap();
+        assert!(batteries.is_empty());
+
+        batteries.source.plugged = true;
+        batteries.refresh().unwrap();
+        assert!(!batteries.is_empty());
+        assert_eq!(batteries.state(), State::Discharging);
+        assert_eq!(batteries.state_of_charge(), 0.25);
+    }
+
+    #[test]
+    fn test_battery_gone_for_a_moment() {
+        let source = Removable::new(vec![reading("1234", State::Discharging, 10.0, 40.0)], true);
+        let mut batteries = Batteries::new(source, &BatterySelection::Aggregate);
+
+        // the refresh fails and the scan finds nothing, so the last values are kept
+        batteries.source.plugged = false;
+        assert!(batteries.refresh().is_err());
+        assert!(batteries.refresh().is_err());
+        assert!(!batteries.is_empty());
+        assert_eq!(batteries.state(), State::Discharging);
+        assert_eq!(batteries.state_of_charge(), 0.25);
+
+        batteries.source.batteries[0].state = State::Charging;
+        batteries.source.plugged = true;
+        batteries.refresh().unwrap();
+        assert_eq!(batteries.state(), State::Charging);
+    }
+
+    #[test]
+    fn test_selection() {
+        let source = vec![
+            reading("1234", State::Unknown, 40.0, 40.0),
+            reading("5678", State::Discharging, 10.0, 40.0),
+        ];
+
+        let mut batteries = Batteries::new(source, &BatterySelection::Aggregate);
+        assert_eq!(batteries.state(), State::Discharging);
+        assert_eq!(batteries.state_of_charge(), 0.625);
+
+        batteries.select(&BatterySelection::Named("5678".to_string()));
+        assert_eq!(batteries.state_of_charge(), 0.25);
+        batteries.select(&BatterySelection::Named("1234".to_string()));
+        assert_eq!(batteries.state(), State::Unknown);
+
+        // a battery that isn't there falls back to all of them
+        batteries.select(&BatterySelection::Named("ABCD".to_string()));
+        assert_eq!(batteries.state_of_charge(), 0.625);
+    }
+
+    #[test]
+    fn test_aggregate_soc() {
+        // one battery is just its own charge
+        assert_eq!(aggregate_soc(&[(50.0, 100.0)]), Some(0.5));
+
+        // a big full battery and a small empty one
+        assert_eq!(aggregate_soc(&[(300.0, 300.0), (0.0, 100.0)]), Some(0.75));
+
+        // the same charge percentage on both is that percentage overall
+        assert_eq!(aggregate_soc(&[(20.0, 80.0), (5.0, 20.0)]), Some(0.25));
+
+        assert_eq!(aggregate_soc(&[(0.0, 0.0)]), None);
+        assert_eq!(aggregate_soc(&[]), None);
+    }
+}
@@ -0,0 +1,202 @@
+/*  Contains the Clock the daemon tells the time with and sleeps on.
+ *
+ *  The daemon runs on the SystemClock. The tests, and the replay of a trace (see trace::simulate),
+ *  use the ManualClock below instead, whose time only moves as far as it is let, so hours of
+ *  battery drain, fades and override timeouts can be run through in milliseconds.
+ *
+ * */
+
+use crate::daemon::events::{Event, Events};
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+pub trait Clock {
+    // monotonic time, for fades, overrides and the watchdog
+    fn now(&self) -> Instant;
+    // the time of day, for the timestamps in a trace
+    fn system_time(&self) -> SystemTime;
+    fn sleep(&self, duration: Duration);
+    // waits up to timeout for something to happen, see Events::wait
+    fn wait(&self, events: &Events, timeout: Duration) -> io::Result<Vec<Event>>;
+}
+
+/* The real time */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    fn wait(&self, events: &Events, timeout: Duration) -> io::Result<Vec<Event>> {
+        events.wait(self, timeout)
+    }
+}
+
+pub mod manual {
+    use super::*;
+    use std::sync::{Arc, Condvar, Mutex};
+
+    // how long run_for gives the daemon to catch up before the test fails
+    #[cfg(test)]
+    const CATCH_UP: Duration = Duration::from_secs(10);
+    // how often a blocked wait looks for events
+    const POLL: Duration = Duration::from_millis(5);
+
+    /* A clock that only moves when something sleeps or waits on it, and then without taking any
+     * real time. Waits stop where the clock is let go to with run_for or allow, and block there
+     * until it is let go further, still looking for events meanwhile, so a daemon on this clock can
+     * be told to quit. Sleeps are short, i.e: a step of a fade, so they never block.
+     * */
+    #[derive(Clone)]
+    pub struct ManualClock(Arc<(Mutex<Time>, Condvar)>);
+
+    struct Time {
+        start: Instant,
+        start_time: SystemTime,
+        elapsed: Duration,
+        // how far waits can go
+        until: Duration,
+        // true while a wait is blocked at until
+        blocked: bool,
+    }
+
+    impl ManualClock {
+        pub fn new() -> Self {
+            ManualClock(Arc::new((
+                Mutex::new(Time {
+                    start: Instant::now(),
+                    start_time: SystemTime::now(),
+                    elapsed: Duration::ZERO,
+                    until: Duration::ZERO,
+                    blocked: false,
+                }),
+                Condvar::new(),
+            )))
+        }
+
+        pub fn elapsed(&self) -> Duration {
+            self.0 .0.lock().unwrap().elapsed
+        }
+
+        /* Lets waits move the clock on to duration past where it is now, without waiting for them
+         * to. For a replay, which waits on the clock itself on the same thread.
+         * */
+        pub fn allow(&self, duration: Duration) {
+            let (time, moved) = &*self.0;
+            let mut time = time.lock().unwrap();
+            time.until = time.until.max(time.elapsed + duration);
+            time.blocked = false;
+            moved.notify_all();
+        }
+
+        /* Lets the clock move on by duration, and waits for the daemon waiting on it to get
+         * there, so everything due by then has been done.
+         * */
+        #[cfg(test)]
+        pub fn run_for(&self, duration: Duration) {
+            let (time, moved) = &*self.0;
+            let mut time = time.lock().unwrap();
+            time.until += duration;
+            time.blocked = false;
+            moved.notify_all();
+
+            let give_up = Instant::now() + CATCH_UP;
+            while !(time.blocked && time.elapsed >= time.until) {
+                assert!(
+                    Instant::now() < give_up,
+                    "the daemon didn't catch up with the clock"
+                );
+                time = moved.wait_timeout(time, POLL).unwrap().0;
+            }
+        }
+
+        /* Moves the clock on to end, if waits can go that far. Otherwise it moves as far as they
+         * can, blocks for a moment, and returns false.
+         * */
+        fn advance(&self, end: Duration) -> bool {
+            let (time, moved) = &*self.0;
+            let mut time = time.lock().unwrap();
+            if end <= time.until {
+                time.elapsed = time.elapsed.max(end);
+                return true;
+            }
+
+            time.elapsed = time.elapsed.max(time.until);
+            time.blocked = true;
+            moved.notify_all();
+            let _ = moved.wait_timeout(time, POLL).unwrap();
+            false
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            let time = self.0 .0.lock().unwrap();
+            time.start + time.elapsed
+        }
+
+        fn system_time(&self) -> SystemTime {
+            let time = self.0 .0.lock().unwrap();
+            time.start_time + time.elapsed
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.0 .0.lock().unwrap().elapsed += duration;
+        }
+
+        fn wait(&self, events: &Events, timeout: Duration) -> io::Result<Vec<Event>> {
+            let end = self.elapsed() + timeout;
+            loop {
+                let happened = events.wait(self, Duration::ZERO)?;
+                if !happened.is_empty() || self.advance(end) {
+                    return Ok(happened);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::manual::ManualClock;
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let (start, start_time) = (clock.now(), clock.system_time());
+
+        // a sleep takes no real time, and moves both clocks
+        let real = Instant::now();
+        clock.sleep(Duration::from_secs(3600));
+        assert!(real.elapsed() < Duration::from_secs(1));
+        assert_eq!(clock.now() - start, Duration::from_secs(3600));
+        assert_eq!(
+            clock.system_time().duration_since(start_time).unwrap(),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(clock.elapsed(), Duration::from_secs(3600));
+
+        // a wait the clock is allowed to finish doesn't block
+        let events = Events::none();
+        clock.allow(Duration::from_secs(60));
+        let real = Instant::now();
+        assert!(clock
+            .wait(&events, Duration::from_secs(60))
+            .unwrap()
+            .is_empty());
+        assert!(real.elapsed() < Duration::from_secs(1));
+        assert_eq!(clock.elapsed(), Duration::from_secs(3660));
+    }
+}
@@ -5,54 +5,744 @@
  *
  * */
 
-use serde::Deserialize;
+use crate::daemon::fade::Easing;
+use crate::error;
+use rules::Rules;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
 use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub mod rules;
+pub mod watch;
+
+/* A brightness level from the config file.
+ *
+ * Either a raw value written straight to the backlight (i.e: 240), or a percentage of the
+ * device's max_brightness (i.e: "60%"). Raw values are what older configs use, percentages
+ * let one config work on panels with different max_brightness values.
+ * */
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(try_from = "LevelRepr")]
+pub enum Level {
+    Raw(u32),
+    Percent(f32),
+}
+
+// What a level looks like in the toml file before we validate it
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LevelRepr {
+    Raw(u32),
+    Text(String),
+}
+
+impl TryFrom<LevelRepr> for Level {
+    type Error = String;
+
+    fn try_from(repr: LevelRepr) -> Result<Self, Self::Error> {
+        match repr {
+            LevelRepr::Raw(v) => Ok(Level::Raw(v)),
+            LevelRepr::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl std::str::FromStr for Level {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        match text.strip_suffix('%') {
+            Some(perc) => match perc.trim().parse::<f32>() {
+                Ok(p) if (0.0..=100.0).contains(&p) => Ok(Level::Percent(p)),
+                Ok(p) => Err(format!(
+                    "brightness percentage {}% is not between 0% and 100%",
+                    p
+                )),
+                Err(_) => Err(format!("invalid brightness percentage \"{}\"", text)),
+            },
+            None => text.parse::<u32>().map(Level::Raw).map_err(|_| {
+                format!(
+                    "invalid brightness \"{}\", expected a number or a percentage like \"60%\"",
+                    text
+                )
+            }),
+        }
+    }
+}
+
+impl Level {
+    /* Returns the raw brightness value for a device with the given max_brightness.
+     * Raw levels are returned unchanged, so a raw value above max_brightness is still
+     * reported as an error by the device.
+     * */
+    pub fn resolve(&self, max_brightness: u32) -> u32 {
+        match self {
+            Level::Raw(v) => *v,
+            Level::Percent(p) => (max_brightness as f32 * p / 100.0).round() as u32,
+        }
+    }
+}
+
+// raw levels are written as numbers and percentages as "60%", the same way the config file has them
+impl Serialize for Level {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Level::Raw(v) => serializer.serialize_u32(*v),
+            Level::Percent(_) => serializer.serialize_str(&self.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Level::Raw(v) => write!(f, "{}", v),
+            Level::Percent(p) => write!(f, "{}%", p),
+        }
+    }
+}
+
+/* Which batteries the daemon looks at, set with battery = "..." in the config file.
+ *
+ * "aggregate" (the default) combines every battery. Anything else is the model or serial number
+ * of the one battery to use.
+ * */
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+#[serde(from = "String")]
+pub enum BatterySelection {
+    #[default]
+    Aggregate,
+    Named(String),
+}
+
+impl Serialize for BatterySelection {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            BatterySelection::Aggregate => serializer.serialize_str("aggregate"),
+            BatterySelection::Named(name) => serializer.serialize_str(name),
+        }
+    }
+}
+
+impl From<String> for BatterySelection {
+    fn from(name: String) -> Self {
+        match name.trim() {
+            "aggregate" => BatterySelection::Aggregate,
+            name => BatterySelection::Named(name.to_string()),
+        }
+    }
+}
+
+/* A brightness curve over the battery's state of charge, from the [curve] section.
+ *
+ * Keys are battery percentages and values are brightness levels, i.e:
+ *   [curve]
+ *   100 = "80%"
+ *   50 = "60%"
+ *   15 = "30%"
+ *
+ * Points are kept sorted by state of charge (as a fraction, 0.0 to 1.0, like BatteryInfo.soc).
+ * */
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(try_from = "BTreeMap<String, Level>")]
+pub struct Curve {
+    points: Vec<(f32, Level)>,
+}
+
+impl TryFrom<BTreeMap<String, Level>> for Curve {
+    type Error = String;
+
+    fn try_from(table: BTreeMap<String, Level>) -> Result<Self, Self::Error> {
+        let mut points = Vec::with_capacity(table.len());
+
+        for (key, level) in table {
+            let perc = key.trim().trim_end_matches('%').trim();
+            match perc.parse::<f32>() {
+                Ok(p) if (0.0..=100.0).contains(&p) => points.push((p / 100.0, level)),
+                _ => {
+                    return Err(format!(
+                        "curve point \"{}\" is not a battery percentage between 0 and 100",
+                        key
+                    ))
+                }
+            }
+        }
+
+        if points.is_empty() {
+            return Err("curve needs at least one point".to_string());
+        }
+
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(Curve { points })
+    }
+}
+
+// written back as a [curve] table, highest battery percentage first like the README has it
+impl Serialize for Curve {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.points.len()))?;
+        for (soc, level) in self.points.iter().rev() {
+            // back to a percentage, without the noise f32 picks up on the way
+            let perc = soc * 100.0;
+            let key = match (perc - perc.round()).abs() < 0.001 {
+                true => format!("{}", perc.round()),
+                false => format!("{:.2}", perc),
+            };
+            map.serialize_entry(&key, level)?;
+        }
+        map.end()
+    }
+}
+
+impl Curve {
+    // the brightness of each point, lowest state of charge first
+    pub fn levels(&self) -> impl Iterator<Item = Level> + '_ {
+        self.points.iter().map(|p| p.1)
+    }
+    /* Returns the brightness for the given state of charge on a device with the given max_brightness.
+     *
+     * Between two points the brightness is interpolated linearly. Below the lowest point or
+     * above the highest one, the brightness of that point is used.
+     * */
+    pub fn brightness_at(&self, soc: f32, max_brightness: u32) -> u32 {
+        let (first, last) = (self.points[0], self.points[self.points.len() - 1]);
+
+        if soc <= first.0 {
+            return first.1.resolve(max_brightness);
+        }
+        if soc >= last.0 {
+            return last.1.resolve(max_brightness);
+        }
+
+        let upper = self.points.iter().position(|p| p.0 >= soc).unwrap();
+        let (lo_soc, lo_level) = self.points[upper - 1];
+        let (hi_soc, hi_level) = self.points[upper];
+
+        let lo = lo_level.resolve(max_brightness) as f32;
+        let hi = hi_level.resolve(max_brightness) as f32;
+        let t = (soc - lo_soc) / (hi_soc - lo_soc);
+
+        (lo + (hi - lo) * t).round() as u32
+    }
+}
 
 // Config struct
 // all values are public so we can access them with the member access operator
-#[derive(Deserialize, PartialEq, Eq, Debug)]
+//
+// The top level values are the brightness table used for every backlight device.
+// Each [[device]] section can override some or all of them for the devices it selects.
+// Brightness values are Levels, so they can be raw values or percentages of max_brightness.
+// Any value left out of the file is taken from Config::default, so a config only needs the
+// values it changes.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
-    pub full: u32,
-    pub low: u32,
+    pub full: Level,
+    pub low: Level,
     pub low_perc: u32,
-    pub charging: u32,
-    pub discharging: u32,
-    pub unknown: u32,
-    pub ac_in: u32,
+    pub charging: Level,
+    pub discharging: Level,
+    pub unknown: Level,
+    pub ac_in: Level,
+    // when set, this replaces low and discharging while the battery is discharging
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub curve: Option<Curve>,
+    // name of the AC supply in /sys/class/power_supply to use, i.e: "ADP1".
+    // If not set, every Mains or USB supply is checked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub power_supply: Option<String>,
+    pub battery: BatterySelection,
+    // seconds between checks of the battery's charge. Plugging and unplugging the charger
+    // is noticed right away through uevents, this only matters for the battery draining.
+    pub poll_interval: u64,
+    // brightness to set when the daemon stops. If not set, the brightness from when the
+    // daemon started is restored.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_exit: Option<Level>,
+    // seconds to leave a brightness the user set by hand alone for. If not set, it is left
+    // alone until the next time the battery state or the AC changes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub override_timeout: Option<u64>,
+    pub fade: FadeConfig,
+    // which brightness each battery state gets, on AC and on battery
+    pub rules: Rules,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub device: Vec<DeviceConfig>,
 }
 
-/* Returns a config struct with the user config values
- * If there is no config file, or the ENV var is not set: a default config is supplied to serde
- *
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            full: Level::Raw(225),
+            low: Level::Raw(100),
+            low_perc: 25,
+            charging: Level::Raw(255),
+            discharging: Level::Raw(155),
+            unknown: Level::Raw(155),
+            ac_in: Level::Raw(225),
+            device: Vec::new(),
+            fade: FadeConfig::default(),
+            curve: None,
+            power_supply: None,
+            battery: BatterySelection::Aggregate,
+            poll_interval: 30,
+            on_exit: None,
+            override_timeout: None,
+            rules: Rules::default(),
+        }
+    }
+}
+
+// The [fade] section in the config file.
+// A duration of 0 (the default) changes brightness right away, like older versions did.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FadeConfig {
+    pub duration_ms: u64,
+    pub easing: Easing,
+}
+
+impl FadeConfig {
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+}
+
+// A [[device]] section in the config file.
+// name is either a sysfs backlight name (i.e: intel_backlight) or a glob (i.e: acpi_video*).
+// Any value left out falls back to the top level value.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub full: Option<Level>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub low: Option<Level>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub low_perc: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charging: Option<Level>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discharging: Option<Level>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unknown: Option<Level>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ac_in: Option<Level>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub curve: Option<Curve>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_exit: Option<Level>,
+}
+
+impl Config {
+    /* Returns the brightness table for the backlight device with the given sysfs name.
+     *
+     * The first [[device]] section whose name matches the device is layered over the top level
+     * values. If no section matches, the top level values are used as is.
+     * */
+    pub fn for_device(&self, device: &str) -> Config {
+        let section = self.device.iter().find(|d| glob_match(&d.name, device));
+
+        match section {
+            Some(d) => Config {
+                full: d.full.unwrap_or(self.full),
+                low: d.low.unwrap_or(self.low),
+                low_perc: d.low_perc.unwrap_or(self.low_perc),
+                charging: d.charging.unwrap_or(self.charging),
+                discharging: d.discharging.unwrap_or(self.discharging),
+                unknown: d.unknown.unwrap_or(self.unknown),
+                ac_in: d.ac_in.unwrap_or(self.ac_in),
+                device: Vec::new(),
+                fade: self.fade,
+                curve: d.curve.clone().or_else(|| self.curve.clone()),
+                power_supply: self.power_supply.clone(),
+                battery: self.battery.clone(),
+                poll_interval: self.poll_interval,
+                on_exit: d.on_exit.or(self.on_exit),
+                override_timeout: self.override_timeout,
+                rules: self.rules,
+            },
+            None => Config {
+                device: Vec::new(),
+                ..self.clone()
+            },
+        }
+    }
+
+    pub fn override_timeout(&self) -> Option<Duration> {
+        self.override_timeout.map(Duration::from_secs)
+    }
+
+    /* Returns what's wrong with the values in the config that toml can't catch on its own.
+     * Values that depend on the backlight are checked by check_device instead.
+     * */
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.low_perc > 100 {
+            problems.push(format!("low_perc = {} is above 100", self.low_perc));
+        }
+        for section in &self.device {
+            match section.low_perc {
+                Some(perc) if perc > 100 => problems.push(format!(
+                    "low_perc = {} in the [[device]] section for {} is above 100",
+                    perc, section.name
+                )),
+                _ => {}
+            }
+        }
+        if self.poll_interval == 0 {
+            problems.push("poll_interval must be at least 1 second".to_string());
+        }
+
+        problems
+    }
+
+    /* Returns the raw brightness values that are above the max_brightness of the named device,
+     * after its [[device]] section is layered on.
+     * */
+    pub fn check_device(&self, name: &str, max_brightness: u32) -> Vec<String> {
+        let config = self.for_device(name);
+        let mut levels = vec![
+            ("full", config.full),
+            ("low", config.low),
+            ("charging", config.charging),
+            ("discharging", config.discharging),
+            ("unknown", config.unknown),
+            ("ac_in", config.ac_in),
+        ];
+        levels.extend(config.on_exit.map(|level| ("on_exit", level)));
+        if let Some(curve) = &config.curve {
+            levels.extend(curve.levels().map(|level| ("a [curve] point", level)));
+        }
+
+        levels
+            .into_iter()
+            .filter_map(|(key, level)| match level {
+                Level::Raw(v) if v > max_brightness => Some(format!(
+                    "{} = {} is above the max brightness of {} ({})",
+                    key, v, name, max_brightness
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/* Returns true if name matches the glob pattern.
+ * Supports '*' (any run of characters) and '?' (exactly one character), which is
+ * all we need to select sysfs backlight names.
  * */
-pub fn load_config(path: String) -> Config {
-    const DEFAULT: Config = Config {
-        full: 225,
-        low: 100,
-        low_perc: 25,
-        charging: 255,
-        discharging: 155,
-        unknown: 155,
-        ac_in: 225,
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None; // last '*' seen, and where in name it started matching
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // let the last '*' eat one more character and try again
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// Why a config file couldn't be loaded
+#[derive(Debug)]
+pub enum ConfigError {
+    // there is no file at the path
+    Missing,
+    Io(io::Error),
+    // the file isn't valid toml, or has a key or value we don't understand.
+    // line and column start at 1.
+    Parse {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    // the file is valid, but some values are out of range
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Missing => write!(f, "the file doesn't exist"),
+            ConfigError::Io(e) => write!(f, "{}", e),
+            ConfigError::Parse {
+                line,
+                column,
+                message,
+            } => write!(f, "line {}, column {}: {}", line, column, message),
+            ConfigError::Invalid(problems) => write!(f, "{}", problems.join("\n")),
+        }
+    }
+}
+
+/* Parses and validates the text of a config file */
+pub fn parse_config(text: &str) -> Result<Config, ConfigError> {
+    let config: Config = toml::from_str(text).map_err(|e| parse_error(text, &e))?;
+
+    let problems = config.validate();
+    if !problems.is_empty() {
+        return Err(ConfigError::Invalid(problems));
+    }
+    Ok(config)
+}
+
+// turns a toml error into a ConfigError::Parse, with a suggestion if a key or value is misspelled
+fn parse_error(text: &str, e: &toml::de::Error) -> ConfigError {
+    let (line, column) = match e.span() {
+        Some(span) => line_column(text, span.start),
+        None => (1, 1),
     };
 
-    let contents = match fs::read_to_string(path) {
-        Ok(stuff) => stuff,
-        Err(e) => e.to_string(),
+    ConfigError::Parse {
+        line,
+        column,
+        message: suggest(e.message()),
+    }
+}
+
+// the line and column of the byte at offset in text, both starting at 1
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = match before.rfind('\n') {
+        Some(newline) => before[newline + 1..].chars().count() + 1,
+        None => before.chars().count() + 1,
+    };
+    (line, column)
+}
+
+/* Rewrites serde's "unknown field `discharing`, expected one of `full`, `low`, ..." messages to
+ * suggest the closest key, i.e: "unknown key `discharing`, did you mean `discharging`?".
+ * Other messages are returned as they are.
+ * */
+fn suggest(message: &str) -> String {
+    let (what, rest) = if let Some(rest) = message.strip_prefix("unknown field ") {
+        ("key", rest)
+    } else if let Some(rest) = message.strip_prefix("unknown variant ") {
+        ("value", rest)
+    } else {
+        return message.to_string();
+    };
+
+    // every name in the message is in backticks, the first one is what was given
+    let names: Vec<&str> = rest.split('`').skip(1).step_by(2).collect();
+    let (given, expected) = match names.split_first() {
+        Some((given, expected)) => (*given, expected),
+        None => return message.to_string(),
+    };
+
+    // a key with its unit left off, like `duration` for `duration_ms`, counts as close
+    let closest = expected
+        .iter()
+        .map(|name| match name.starts_with(given) {
+            true => (0, *name),
+            false => (edit_distance(given, name), *name),
+        })
+        .min();
+    match closest {
+        Some((distance, name)) if distance <= 2.max(given.len() / 3) => {
+            format!("unknown {} `{}`, did you mean `{}`?", what, given, name)
+        }
+        _ => format!(
+            "unknown {} `{}`, expected one of {}",
+            what,
+            given,
+            expected
+                .iter()
+                .map(|name| format!("`{}`", name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+// the Levenshtein distance between a and b
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+// the directory under $XDG_CONFIG_HOME, $HOME/.config and $XDG_CONFIG_DIRS our config is in
+const CONFIG_DIR: &str = "GammaDaemon";
+const CONFIG_NAME: &str = "conf.toml";
+// read last of all, so a machine wide config can be set up without knowing anyone's home
+pub const SYSTEM_CONFIG: &str = "/etc/gamma_daemon/conf.toml";
+
+/* Returns every place a config file is looked for, most important first:
+ * the path given on the command line, $XDG_CONFIG_HOME/GammaDaemon/conf.toml,
+ * $HOME/.config/GammaDaemon/conf.toml, GammaDaemon/conf.toml in each of $XDG_CONFIG_DIRS
+ * (/etc/xdg if it isn't set), and SYSTEM_CONFIG.
+ * */
+pub fn search_paths(explicit: Option<&str>) -> Vec<PathBuf> {
+    let var = |name| env::var(name).ok().filter(|v: &String| !v.is_empty());
+    search_paths_from(
+        explicit,
+        var("XDG_CONFIG_HOME"),
+        var("HOME"),
+        var("XDG_CONFIG_DIRS"),
+    )
+}
+
+fn search_paths_from(
+    explicit: Option<&str>,
+    config_home: Option<String>,
+    home: Option<String>,
+    config_dirs: Option<String>,
+) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = explicit.map(PathBuf::from).into_iter().collect();
+    let in_dir = |dir: &str| Path::new(dir).join(CONFIG_DIR).join(CONFIG_NAME);
+
+    if let Some(config_home) = config_home {
+        paths.push(in_dir(&config_home));
+    }
+    if let Some(home) = home {
+        paths.push(in_dir(&format!("{}/.config", home)));
+    }
+    let config_dirs = config_dirs.unwrap_or_else(|| "/etc/xdg".to_string());
+    // relative entries are meant to be ignored, see the XDG base directory spec
+    for dir in config_dirs.split(':').filter(|d| d.starts_with('/')) {
+        paths.push(in_dir(dir));
+    }
+    paths.push(PathBuf::from(SYSTEM_CONFIG));
+
+    // $XDG_CONFIG_HOME is usually $HOME/.config, there's no point reading it twice
+    let mut seen = Vec::new();
+    paths.retain(|p| match seen.contains(p) {
+        true => false,
+        false => {
+            seen.push(p.clone());
+            true
+        }
+    });
+    paths
+}
+
+/* A config, and the files it was read from */
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadedConfig {
+    pub config: Config,
+    // least important first, so each file overrides the ones before it.
+    // Empty if none of them exist, in which case config is the default.
+    pub files: Vec<PathBuf>,
+}
+
+/* Reads every file in paths (most important first, like search_paths returns them) that exists,
+ * and layers them over each other and the defaults: a value in a more important file replaces the
+ * same value in a less important one, and anything no file sets keeps its default.
+ *
+ * required is the file given on the command line, if any. Unlike the others it has to exist, so a
+ * typo in its path is an error instead of a daemon quietly running on the other files.
+ *
+ * Each file is checked on its own first, so errors point at the file and line they are on.
+ * */
+pub fn load_layered(
+    paths: &[PathBuf],
+    required: Option<&Path>,
+) -> Result<LoadedConfig, error::Error> {
+    let file_error = |path: &Path, source| error::Error::Config {
+        path: path.display().to_string(),
+        source,
     };
+    let mut merged = toml::Table::new();
+    let mut files = Vec::new();
+
+    for path in paths.iter().rev() {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match Some(path.as_path()) == required
+            {
+                true => return Err(file_error(path, ConfigError::Missing)),
+                false => continue,
+            },
+            Err(e) => return Err(file_error(path, ConfigError::Io(e))),
+        };
+        parse_config(&text).map_err(|source| file_error(path, source))?;
+
+        // parse_config already read it, so this can't fail
+        let table: toml::Table = toml::from_str(&text).unwrap_or_default();
+        merge_tables(&mut merged, table);
+        files.push(path.clone());
+    }
+
+    // every layer is valid, and merging only swaps whole values, so the result is valid too
+    let config = toml::Value::Table(merged)
+        .try_into::<Config>()
+        .map_err(|e| error::Error::Config {
+            path: describe_files(&files),
+            source: parse_error("", &e),
+        })?;
 
-    match toml::from_str(&contents) {
-        Ok(conf) => conf,
-        Err(e) => {
-            eprintln!(
-                "Error in config file:\n {} \n gamma_daemon will use the default config",
-                e
-            );
-            DEFAULT
+    Ok(LoadedConfig { config, files })
+}
+
+/* Copies every value in over into base. Tables like [fade] are merged key by key, but a [curve]
+ * or the [[device]] list replaces the one below it as a whole, since mixing points or sections
+ * from two files would give a curve or device list neither file describes.
+ * */
+fn merge_tables(base: &mut toml::Table, over: toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(below)), toml::Value::Table(above)) if key != "curve" => {
+                merge_tables(below, above)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
         }
     }
 }
 
+/* Returns the files a config was read from for messages, i.e:
+ * "/etc/gamma_daemon/conf.toml + /home/me/.config/GammaDaemon/conf.toml", or "defaults"
+ * */
+pub fn describe_files(files: &[PathBuf]) -> String {
+    match files.is_empty() {
+        true => "defaults".to_string(),
+        false => files
+            .iter()
+            .map(|f| f.display().to_string())
+            .collect::<Vec<_>>()
+            .join(" + "),
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -60,18 +750,55 @@ mod tests {
 
     #[test]
     fn test_default() {
-        const DEFAULT: Config = Config {
-            full: 225,
-            low: 100,
+        let default = Config {
+            full: Level::Raw(225),
+            low: Level::Raw(100),
             low_perc: 25,
-            charging: 255,
-            discharging: 155,
-            unknown: 155,
-            ac_in: 225,
+            charging: Level::Raw(255),
+            discharging: Level::Raw(155),
+            unknown: Level::Raw(155),
+            ac_in: Level::Raw(225),
+            ..Default::default()
         };
-            let test_config: Config =
-                load_config("a path that doesnt have the file in it".to_string());
-            assert_eq!(test_config, DEFAULT);
+        let missing = [PathBuf::from("a path that doesnt have the file in it")];
+        let loaded = load_layered(&missing, None).unwrap();
+        assert_eq!(loaded.config, default);
+        assert!(loaded.files.is_empty());
+    }
+
+    #[test]
+    fn test_partial_config() {
+        // only the values given change, the rest keep their defaults
+        let config =
+            parse_config("discharging = \"40%\"\n[fade]\neasing = \"ease-out\"\n").unwrap();
+        assert_eq!(
+            config,
+            Config {
+                discharging: Level::Percent(40.0),
+                fade: FadeConfig {
+                    duration_ms: 0,
+                    easing: Easing::EaseOut,
+                },
+                ..Config::default()
+            }
+        );
+
+        assert_eq!(parse_config("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_print_config() {
+        let text = toml::to_string(&Config::default()).unwrap();
+        assert!(text.starts_with("full = 225\nlow = 100\nlow_perc = 25\n"));
+        assert!(text.contains("battery = \"aggregate\"\n"));
+        assert!(!text.contains("on_exit"));
+        assert_eq!(parse_config(&text).unwrap(), Config::default());
+
+        // everything that can be set survives being printed and read back
+        let config = parse_config("full = \"95%\"\nbattery = \"BAT1\"\npower_supply = \"ADP1\"\non_exit = 120\noverride_timeout = 600\n[curve]\n100 = \"80%\"\n15 = 40\n[fade]\nduration_ms = 500\neasing = \"perceptual\"\n[[device]]\nname = \"acpi_video*\"\nfull = 15\n[device.curve]\n50 = 7\n").unwrap();
+        let text = toml::to_string(&config).unwrap();
+        assert!(text.contains("[curve]\n100 = \"80%\"\n15 = 40\n"));
+        assert_eq!(parse_config(&text).unwrap(), config);
     }
 
     #[test]
@@ -81,35 +808,407 @@ mod tests {
         let temp_file_path = "../../test_config.toml".to_string();
         fs::write(&temp_file_path, temp_config).expect("Failed to write temporary config file");
 
-        let test_config: Config = load_config(temp_file_path.clone());
+        let paths = [PathBuf::from(&temp_file_path)];
+        let test_config: Config = load_layered(&paths, None).unwrap().config;
         let expected_config = Config {
-            full: 200,
-            low: 50,
+            full: Level::Raw(200),
+            low: Level::Raw(50),
             low_perc: 10,
-            charging: 180,
-            discharging: 90,
-            unknown: 90,
-            ac_in: 200,
+            charging: Level::Raw(180),
+            discharging: Level::Raw(90),
+            unknown: Level::Raw(90),
+            ac_in: Level::Raw(200),
+            ..Default::default()
         };
         assert_eq!(test_config, expected_config);
 
         fs::remove_file(temp_file_path).expect("Failed to remove temporary config file");
     }
 
+    #[test]
+    fn test_device_sections() {
+        let temp_config = "full = 200\nlow = 50\n\n[[device]]\nname = \"intel_backlight\"\nfull = 90000\ndischarging = 40000\n\n[[device]]\nname = \"acpi_video*\"\nlow = 5\n".to_string();
+
+        let config: Config = toml::from_str(&temp_config).unwrap();
+        assert_eq!(config.device.len(), 2);
+
+        let intel = config.for_device("intel_backlight");
+        assert_eq!(intel.full, Level::Raw(90000));
+        assert_eq!(intel.discharging, Level::Raw(40000));
+        assert_eq!(intel.low, Level::Raw(50));
+        assert!(intel.device.is_empty());
+
+        let acpi = config.for_device("acpi_video0");
+        assert_eq!(acpi.low, Level::Raw(5));
+        assert_eq!(acpi.full, Level::Raw(200));
+
+        let other = config.for_device("amdgpu_bl0");
+        assert_eq!(other.full, Level::Raw(200));
+        assert_eq!(other.low, Level::Raw(50));
+    }
+
+    #[test]
+    fn test_percentage_levels() {
+        let temp_config = "full = \"100%\"\nlow = \"12.5%\"\nlow_perc = 10\ncharging = 255\ndischarging = \" 60 % \"\nunknown = \"60%\"\nac_in = \"255\"".to_string();
+
+        let config: Config = toml::from_str(&temp_config).unwrap();
+        assert_eq!(config.full, Level::Percent(100.0));
+        assert_eq!(config.low, Level::Percent(12.5));
+        assert_eq!(config.charging, Level::Raw(255));
+        assert_eq!(config.discharging, Level::Percent(60.0));
+        assert_eq!(config.ac_in, Level::Raw(255));
+
+        assert_eq!(config.full.resolve(96000), 96000);
+        assert_eq!(config.low.resolve(96000), 12000);
+        assert_eq!(config.discharging.resolve(255), 153);
+        assert_eq!(config.charging.resolve(96000), 255);
+    }
+
+    #[test]
+    fn test_invalid_levels() {
+        assert!("150%".parse::<Level>().is_err());
+        assert!("-5%".parse::<Level>().is_err());
+        assert!("bright".parse::<Level>().is_err());
+        assert!("%".parse::<Level>().is_err());
+
+        let temp_config = "full = \"120%\"\nlow = 100\nlow_perc = 10\ncharging = 255\ndischarging = 155\nunknown = 155\nac_in = 255".to_string();
+        assert!(toml::from_str::<Config>(&temp_config).is_err());
+    }
+
+    #[test]
+    fn test_fade_section() {
+        let temp_config = "[fade]\nduration_ms = 750\neasing = \"ease-out\"\n".to_string();
+
+        let config: Config = toml::from_str(&temp_config).unwrap();
+        assert_eq!(config.fade.duration(), Duration::from_millis(750));
+        assert_eq!(config.fade.easing, Easing::EaseOut);
+
+        let temp_config = "[fade]\neasing = \"log\"\n".to_string();
+
+        let config: Config = toml::from_str(&temp_config).unwrap();
+        assert_eq!(config.fade.duration(), Duration::ZERO);
+        assert_eq!(config.fade.easing, Easing::Perceptual);
+    }
+
+    #[test]
+    fn test_curve_section() {
+        let temp_config = "[curve]\n100 = \"80%\"\n\"50%\" = \"60%\"\n15 = \"30%\"\n\n[[device]]\nname = \"acpi_video0\"\n\n[device.curve]\n100 = 255\n0 = 0\n".to_string();
+
+        let config: Config = toml::from_str(&temp_config).unwrap();
+        let curve = config.curve.as_ref().unwrap();
+
+        // above and below the ends of the curve
+        assert_eq!(curve.brightness_at(1.0, 1000), 800);
+        assert_eq!(curve.brightness_at(0.1, 1000), 300);
+        assert_eq!(curve.brightness_at(0.0, 1000), 300);
+
+        // on and between points
+        assert_eq!(curve.brightness_at(0.5, 1000), 600);
+        assert_eq!(curve.brightness_at(0.75, 1000), 700);
+        assert_eq!(curve.brightness_at(0.325, 1000), 450);
+
+        // device sections can have their own curve
+        let acpi = config.for_device("acpi_video0");
+        assert_eq!(acpi.curve.unwrap().brightness_at(0.5, 255), 128);
+        let intel = config.for_device("intel_backlight");
+        assert_eq!(intel.curve.as_ref(), Some(curve));
+    }
+
+    #[test]
+    fn test_invalid_curve() {
+        assert!(toml::from_str::<Config>("[curve]\nhalf = \"50%\"\n").is_err());
+        assert!(toml::from_str::<Config>("[curve]\n120 = \"50%\"\n").is_err());
+        // an empty curve
+        assert!(toml::from_str::<Config>("[curve]\n").is_err());
+    }
+
+    #[test]
+    fn test_pinned_power_supply() {
+        let temp_config = "power_supply = \"ADP1\"\n".to_string();
+
+        let config: Config = toml::from_str(&temp_config).unwrap();
+        assert_eq!(config.power_supply.as_deref(), Some("ADP1"));
+        assert_eq!(Config::default().power_supply, None);
+    }
+
+    #[test]
+    fn test_poll_interval() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.poll_interval, 30);
+
+        let config: Config = toml::from_str("poll_interval = 5\n").unwrap();
+        assert_eq!(config.poll_interval, 5);
+    }
+
+    #[test]
+    fn test_battery_selection() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.battery, BatterySelection::Aggregate);
+
+        let config: Config = toml::from_str("battery = \"aggregate\"\n").unwrap();
+        assert_eq!(config.battery, BatterySelection::Aggregate);
+
+        let config: Config = toml::from_str("battery = \"45N1041\"\n").unwrap();
+        assert_eq!(
+            config.battery,
+            BatterySelection::Named("45N1041".to_string())
+        );
+    }
+
+    #[test]
+    fn test_on_exit() {
+        let temp_config =
+            "on_exit = \"50%\"\n\n[[device]]\nname = \"acpi_video0\"\non_exit = 10\n".to_string();
+
+        let config: Config = toml::from_str(&temp_config).unwrap();
+        assert_eq!(config.on_exit, Some(Level::Percent(50.0)));
+        assert_eq!(
+            config.for_device("acpi_video0").on_exit,
+            Some(Level::Raw(10))
+        );
+        assert_eq!(
+            config.for_device("intel_backlight").on_exit,
+            Some(Level::Percent(50.0))
+        );
+        assert_eq!(Config::default().on_exit, None);
+    }
+
+    #[test]
+    fn test_override_timeout() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.override_timeout(), None);
+
+        let config: Config = toml::from_str("override_timeout = 600\n").unwrap();
+        assert_eq!(config.override_timeout(), Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("intel_backlight", "intel_backlight"));
+        assert!(!glob_match("intel_backlight", "intel_backlight2"));
+        assert!(glob_match("acpi_video*", "acpi_video0"));
+        assert!(glob_match("acpi_video*", "acpi_video"));
+        assert!(glob_match("*", "amdgpu_bl0"));
+        assert!(glob_match("amdgpu_bl?", "amdgpu_bl1"));
+        assert!(!glob_match("amdgpu_bl?", "amdgpu_bl"));
+        assert!(glob_match("*_bl*", "amdgpu_bl0"));
+        assert!(!glob_match("acpi*", "intel_backlight"));
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let temp_file_path = std::env::temp_dir().join(format!(
+            "gamma_daemon_read_config_{}.toml",
+            std::process::id()
+        ));
+        fs::write(&temp_file_path, "full = \"loud\"").unwrap();
+
+        // a broken file isn't swapped for the default config
+        let invalid = load_layered(std::slice::from_ref(&temp_file_path), None);
+        assert!(matches!(
+            invalid,
+            Err(error::Error::Config {
+                source: ConfigError::Parse { line: 1, .. },
+                ..
+            })
+        ));
+
+        fs::remove_file(temp_file_path).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        // a couple of keys first, so the line numbers count them
+        let base = "full = 200\nlow = 50\n";
+
+        let error = parse_config(&format!("{}discharing = 80\n", base)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 3, column 1: unknown key `discharing`, did you mean `discharging`?"
+        );
+
+        let error = parse_config(&format!("{}[fade]\nduration = 500\n", base)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 4, column 1: unknown key `duration`, did you mean `duration_ms`?"
+        );
+
+        let error = parse_config(&format!(
+            "{}[[device]]\nname = \"intel_backlight\"\nful = 9000\n",
+            base
+        ))
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("line 5, column 1: unknown key `ful`, did you mean `full`?"));
+
+        let error = parse_config(&format!("{}[fade]\neasing = \"eas-out\"\n", base)).unwrap_err();
+        assert!(error.to_string().contains("did you mean `ease-out`?"));
+
+        // nothing close, so list what we know
+        let error = parse_config(&format!("{}brightness_when_sad = 3\n", base)).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("unknown key `brightness_when_sad`, expected one of `full`, `low`"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let error = parse_config("low_perc = 110\npoll_interval = 0\n[[device]]\nname = \"acpi_video0\"\nlow_perc = 101\n")
+            .unwrap_err();
+        match error {
+            ConfigError::Invalid(problems) => assert_eq!(
+                problems,
+                vec![
+                    "low_perc = 110 is above 100",
+                    "low_perc = 101 in the [[device]] section for acpi_video0 is above 100",
+                    "poll_interval must be at least 1 second",
+                ]
+            ),
+            e => panic!("expected Invalid, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_check_device() {
+        let config = parse_config("full = 90000\nlow = \"10%\"\n[[device]]\nname = \"acpi_video0\"\nfull = 15\ncharging = 15\ndischarging = 10\nunknown = 10\nac_in = 15\n[device.curve]\n100 = 20\n0 = 1\n")
+            .unwrap();
+
+        assert_eq!(
+            config.check_device("intel_backlight", 96000),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            config.check_device("amdgpu_bl0", 255),
+            vec!["full = 90000 is above the max brightness of amdgpu_bl0 (255)"]
+        );
+        assert_eq!(
+            config.check_device("acpi_video0", 15),
+            vec!["a [curve] point = 20 is above the max brightness of acpi_video0 (15)"]
+        );
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("discharing", "discharging"), 1);
+        assert_eq!(edit_distance("low", "low"), 0);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
     #[test]
     fn test_missing_config_file() {
         std::env::remove_var("USER");
         let temp_file_path = "missing_test_config.toml".to_string();
-        let test_config: Config = load_config(temp_file_path);
+        let test_config: Config = load_layered(&[PathBuf::from(temp_file_path)], None)
+            .unwrap()
+            .config;
         let expected_config = Config {
-            full: 225,
-            low: 100,
+            full: Level::Raw(225),
+            low: Level::Raw(100),
             low_perc: 25,
-            charging: 255,
-            discharging: 155,
-            unknown: 155,
-            ac_in: 225,
+            charging: Level::Raw(255),
+            discharging: Level::Raw(155),
+            unknown: Level::Raw(155),
+            ac_in: Level::Raw(225),
+            ..Default::default()
         };
         assert_eq!(test_config, expected_config);
     }
+
+    #[test]
+    fn test_search_paths() {
+        let paths = search_paths_from(
+            Some("my.toml"),
+            Some("/home/me/.config".to_string()),
+            Some("/home/me".to_string()),
+            Some("/usr/local/etc/xdg:relative:/etc/xdg".to_string()),
+        );
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("my.toml"),
+                PathBuf::from("/home/me/.config/GammaDaemon/conf.toml"),
+                PathBuf::from("/usr/local/etc/xdg/GammaDaemon/conf.toml"),
+                PathBuf::from("/etc/xdg/GammaDaemon/conf.toml"),
+                PathBuf::from("/etc/gamma_daemon/conf.toml"),
+            ]
+        );
+
+        // root, with nothing set but $HOME
+        let paths = search_paths_from(None, None, Some("/root".to_string()), None);
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("/root/.config/GammaDaemon/conf.toml"),
+                PathBuf::from("/etc/xdg/GammaDaemon/conf.toml"),
+                PathBuf::from("/etc/gamma_daemon/conf.toml"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_layered_config() {
+        let dir = std::env::temp_dir().join(format!("gamma_daemon_layers_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let user = dir.join("user.toml");
+        let system = dir.join("system.toml");
+        let missing = dir.join("missing.toml");
+
+        fs::write(
+            &system,
+            "full = 200\nlow = 40\n[fade]\nduration_ms = 500\n[curve]\n100 = 200\n0 = 10\n",
+        )
+        .unwrap();
+        fs::write(
+            &user,
+            "low = 60\n[fade]\neasing = \"ease-out\"\n[curve]\n50 = 100\n",
+        )
+        .unwrap();
+
+        let loaded = load_layered(&[missing.clone(), user.clone(), system.clone()], None).unwrap();
+        assert_eq!(loaded.files, vec![system.clone(), user.clone()]);
+        assert_eq!(loaded.config.full, Level::Raw(200));
+        assert_eq!(loaded.config.low, Level::Raw(60));
+        assert_eq!(loaded.config.discharging, Config::default().discharging);
+        // [fade] is merged, but the user's curve replaces the system one
+        assert_eq!(
+            loaded.config.fade,
+            FadeConfig {
+                duration_ms: 500,
+                easing: Easing::EaseOut,
+            }
+        );
+        assert_eq!(
+            loaded.config.curve.unwrap().levels().collect::<Vec<_>>(),
+            vec![Level::Raw(100)]
+        );
+
+        // errors name the file they are in
+        fs::write(&user, "low = 60\nlwo = 50\n").unwrap();
+        let error = load_layered(&[user.clone(), system.clone()], None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Error in {}: line 2, column 1: unknown key `lwo`, did you mean `low`?",
+                user.display()
+            )
+        );
+
+        let loaded = load_layered(std::slice::from_ref(&missing), None).unwrap();
+        assert_eq!(describe_files(&loaded.files), "defaults");
+
+        // but a file given on the command line has to exist
+        let error = load_layered(&[missing.clone(), system.clone()], Some(&missing)).unwrap_err();
+        assert!(matches!(
+            error,
+            error::Error::Config {
+                source: ConfigError::Missing,
+                ..
+            }
+        ));
+        assert_eq!(error.exit_code(), error::EXIT_CONFIG);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
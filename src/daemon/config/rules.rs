@@ -0,0 +1,151 @@
+/*  Contains the [rules] section of the config file, which picks the brightness for each battery
+ *  state, plugged in and unplugged.
+ *
+ *  i.e:
+ *    [rules]
+ *    unknown = { ac = "unknown", battery = "unknown" }
+ *    full = { ac = "ac_in", battery = "draining" }
+ *
+ *  Every state left out keeps its default rule, and the defaults are what gamma_daemon always did,
+ *  so a config without [rules] behaves like it did before.
+ *
+ * */
+
+use crate::daemon::config::{Config, Level};
+use battery::State;
+use serde::{Deserialize, Serialize};
+
+/* Which value from the brightness table a rule sets */
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    Full,
+    Low,
+    Charging,
+    Discharging,
+    Unknown,
+    AcIn,
+    // the [curve] if there is one, otherwise low below low_perc and discharging above it
+    Draining,
+}
+
+impl Target {
+    /* Returns the level this target sets in config, or None for Draining, which depends on the
+     * battery's charge.
+     * */
+    pub fn level(&self, config: &Config) -> Option<Level> {
+        match self {
+            Target::Full => Some(config.full),
+            Target::Low => Some(config.low),
+            Target::Charging => Some(config.charging),
+            Target::Discharging => Some(config.discharging),
+            Target::Unknown => Some(config.unknown),
+            Target::AcIn => Some(config.ac_in),
+            Target::Draining => None,
+        }
+    }
+}
+
+/* What to set for one battery state, on AC and on battery */
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub ac: Target,
+    pub battery: Target,
+}
+
+impl Rule {
+    const fn both(target: Target) -> Rule {
+        Rule {
+            ac: target,
+            battery: target,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    pub full: Rule,
+    pub charging: Rule,
+    pub discharging: Rule,
+    pub empty: Rule,
+    pub unknown: Rule,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            full: Rule::both(Target::Full),
+            charging: Rule::both(Target::Charging),
+            discharging: Rule::both(Target::Draining),
+            empty: Rule::both(Target::Draining),
+            unknown: Rule {
+                ac: Target::AcIn,
+                battery: Target::Discharging,
+            },
+        }
+    }
+}
+
+impl Rules {
+    /* Returns what to set for the battery state, with the AC plugged in or not */
+    pub fn target(&self, state: State, plugged: bool) -> Target {
+        let rule = match state {
+            State::Full => self.full,
+            State::Charging => self.charging,
+            State::Discharging => self.discharging,
+            State::Empty => self.empty,
+            // State::Unknown, and any state the battery crate adds later
+            _ => self.unknown,
+        };
+
+        match plugged {
+            true => rule.ac,
+            false => rule.battery,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_rules() {
+        let rules = Rules::default();
+
+        for plugged in [true, false] {
+            assert_eq!(rules.target(State::Full, plugged), Target::Full);
+            assert_eq!(rules.target(State::Charging, plugged), Target::Charging);
+            assert_eq!(rules.target(State::Discharging, plugged), Target::Draining);
+            assert_eq!(rules.target(State::Empty, plugged), Target::Draining);
+        }
+        assert_eq!(rules.target(State::Unknown, true), Target::AcIn);
+        assert_eq!(rules.target(State::Unknown, false), Target::Discharging);
+    }
+
+    #[test]
+    fn test_rules_section() {
+        let config: Config = toml::from_str(
+            "[rules]\nunknown = { ac = \"unknown\", battery = \"unknown\" }\nfull = { ac = \"ac_in\", battery = \"draining\" }\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.rules.target(State::Unknown, false), Target::Unknown);
+        assert_eq!(config.rules.target(State::Full, true), Target::AcIn);
+        assert_eq!(config.rules.target(State::Full, false), Target::Draining);
+        // the states left out keep their defaults
+        assert_eq!(config.rules.charging, Rules::default().charging);
+
+        assert_eq!(Target::Unknown.level(&config), Some(config.unknown));
+        assert_eq!(Target::Draining.level(&config), None);
+
+        // a rule needs both sides
+        assert!(toml::from_str::<Config>("[rules]\nfull = { ac = \"ac_in\" }\n").is_err());
+        assert!(
+            toml::from_str::<Config>("[rules]\nfull = { ac = \"dim\", battery = \"low\" }\n")
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,210 @@
+/*  Contains an inotify watch on the config files, so the daemon can reload them when they change.
+ *
+ *  Editors often save by writing a new file and renaming it over the old one, which drops any
+ *  watch on the file itself. So instead we watch the directory each config file is in, and look for
+ *  events that name our file.
+ *
+ * */
+
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+// written, or moved into place. Not IN_CREATE, since a newly created file is usually still empty.
+const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+
+pub struct ConfigWatch {
+    fd: RawFd,
+    // the watch descriptor of each file's directory, and the file's name in it
+    files: Vec<(i32, OsString)>,
+}
+
+impl ConfigWatch {
+    /* Watches every path in paths whose directory exists. Files that don't exist yet are
+     * still noticed when they are created, as long as their directory is there.
+     * */
+    pub fn open(paths: &[PathBuf]) -> io::Result<Self> {
+        // SAFETY: plain inotify syscall, the fd is owned by the returned ConfigWatch
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // closes fd if we return early
+        let mut watch = ConfigWatch {
+            fd,
+            files: Vec::new(),
+        };
+
+        for path in paths {
+            let file_name = match path.file_name() {
+                Some(name) => name.to_os_string(),
+                None => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+            };
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            let dir = CString::new(dir.as_os_str().as_bytes())?;
+
+            // adding a directory twice returns the same watch descriptor, which is what we want
+            // SAFETY: dir is a valid C string
+            let wd = unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), WATCH_MASK) };
+            if wd < 0 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::NotFound => continue,
+                    _ => return Err(e),
+                }
+            }
+            watch.files.push((wd, file_name));
+        }
+
+        match watch.files.is_empty() {
+            true => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "none of the config directories exist",
+            )),
+            false => Ok(watch),
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /* Reads every waiting inotify event, and returns true if any of them was for a config file */
+    pub fn changed(&self) -> io::Result<bool> {
+        let mut changed = false;
+        // room for plenty of events with long names
+        let mut buf = [0u8; 4096];
+
+        loop {
+            // SAFETY: buf is valid for buf.len() bytes
+            let len =
+                unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if len < 0 {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(changed),
+                    _ => Err(e),
+                };
+            }
+
+            changed |= event_names(&buf[..len as usize])
+                .iter()
+                .any(|event| self.files.contains(event));
+        }
+    }
+}
+
+impl Drop for ConfigWatch {
+    fn drop(&mut self) {
+        // SAFETY: we own the fd
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/* Returns the watch descriptor and file name of each event in a buffer of inotify events.
+ *
+ * Each event is a struct inotify_event followed by len bytes of nul padded name.
+ * */
+fn event_names(buf: &[u8]) -> Vec<(i32, OsString)> {
+    let header = mem::size_of::<libc::inotify_event>();
+    let mut names = Vec::new();
+    let mut offset = 0;
+
+    while offset + header <= buf.len() {
+        // SAFETY: there are at least header bytes left, and read_unaligned doesn't need alignment
+        let event: libc::inotify_event = unsafe {
+            std::ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event)
+        };
+
+        let start = offset + header;
+        let end = (start + event.len as usize).min(buf.len());
+        let name: Vec<u8> = buf[start..end]
+            .iter()
+            .copied()
+            .take_while(|b| *b != 0)
+            .collect();
+        if !name.is_empty() {
+            names.push((event.wd, OsStr::from_bytes(&name).to_os_string()));
+        }
+
+        offset = end;
+    }
+
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // builds the bytes of one inotify event, with the name nul padded to 16 bytes like the kernel does
+    fn raw_event(name: &str) -> Vec<u8> {
+        let mut padded = name.as_bytes().to_vec();
+        padded.resize(16, 0);
+
+        let event = libc::inotify_event {
+            wd: 1,
+            mask: libc::IN_CLOSE_WRITE,
+            cookie: 0,
+            len: padded.len() as u32,
+        };
+        // SAFETY: inotify_event is plain old data
+        let header: [u8; mem::size_of::<libc::inotify_event>()] = unsafe { mem::transmute(event) };
+
+        let mut bytes = header.to_vec();
+        bytes.extend(padded);
+        bytes
+    }
+
+    #[test]
+    fn test_event_names() {
+        let mut buf = raw_event("conf.toml");
+        buf.extend(raw_event(".conf.toml.swp"));
+
+        assert_eq!(
+            event_names(&buf),
+            vec![
+                (1, OsString::from("conf.toml")),
+                (1, OsString::from(".conf.toml.swp"))
+            ]
+        );
+        assert!(event_names(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_watch_config_file() {
+        let dir = std::env::temp_dir().join(format!("gamma_daemon_watch_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("conf.toml");
+        fs::write(&path, "full = 255").unwrap();
+
+        // the second file's directory doesn't exist, so it's skipped
+        let missing = dir.join("missing").join("conf.toml");
+        let watch = ConfigWatch::open(&[missing.clone(), path.clone()]).unwrap();
+        assert!(ConfigWatch::open(&[missing]).is_err());
+        assert!(!watch.changed().unwrap());
+
+        // some other file in the same directory
+        fs::write(dir.join("other.toml"), "").unwrap();
+        assert!(!watch.changed().unwrap());
+
+        // saving by renaming a new file over the config, like most editors do
+        fs::write(dir.join("conf.toml.new"), "full = 200").unwrap();
+        fs::rename(dir.join("conf.toml.new"), &path).unwrap();
+        assert!(watch.changed().unwrap());
+
+        fs::write(&path, "full = 100").unwrap();
+        assert!(watch.changed().unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
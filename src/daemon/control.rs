@@ -0,0 +1,434 @@
+/*  Contains the control socket, which lets other programs talk to the running daemon.
+ *
+ *  The socket is a Unix socket at $XDG_RUNTIME_DIR/gamma_daemon.sock. A client connects, writes
+ *  one request as a line of JSON, and gets one line of JSON back, i.e:
+ *
+ *      {"command":"set","level":"60%"}
+ *      {"ok":true}
+ *
+ *  The commands are status, set, pause, resume, reload and quit.
+ *
+ * */
+
+use crate::daemon::clock::Clock;
+use crate::daemon::config::Level;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const SOCKET_NAME: &str = "gamma_daemon.sock";
+
+// how long a client gets to send its request before we hang up on it
+const CLIENT_TIMEOUT: Duration = Duration::from_millis(500);
+
+// a request is one short line, anything longer than this isn't one
+const MAX_REQUEST: usize = 4096;
+
+/* A request sent over the control socket */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Request {
+    // reply with what the daemon is doing
+    Status,
+    // set every backlight to level, and leave it there like a change made by hand
+    Set { level: Level },
+    // stop changing the brightness until resumed
+    Pause,
+    // start changing the brightness again, and set it right away
+    Resume,
+    // read the config file again
+    Reload,
+    // restore the brightness and stop the daemon
+    Quit,
+}
+
+/* The reply to a Request. error is only set when ok is false, and status only for Status */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+}
+
+impl Response {
+    pub fn ok() -> Self {
+        Response {
+            ok: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn error(message: String) -> Self {
+        Response {
+            ok: false,
+            error: Some(message),
+            status: None,
+        }
+    }
+
+    pub fn status(status: Status) -> Self {
+        Response {
+            ok: true,
+            error: None,
+            status: Some(status),
+        }
+    }
+}
+
+/* What the daemon is doing, as reported by the status command */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct Status {
+    pub paused: bool,
+    // charging, discharging, full, empty or unknown
+    pub battery_state: String,
+    pub state_of_charge: f32,
+    pub ac_online: bool,
+    // the config files that were read, least important first. Empty if the defaults are in use.
+    pub config_files: Vec<String>,
+    pub devices: Vec<DeviceStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct DeviceStatus {
+    pub name: String,
+    // the brightness we last set, or are fading to
+    pub brightness: Option<u32>,
+    pub max_brightness: u32,
+    // true if the user changed the brightness, so we are leaving it alone
+    pub overridden: bool,
+}
+
+/* Returns where the control socket lives, or None if $XDG_RUNTIME_DIR isn't set */
+pub fn socket_path() -> Option<PathBuf> {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => Some(Path::new(&dir).join(SOCKET_NAME)),
+        _ => None,
+    }
+}
+
+/* Sends request to the running daemon and waits for its reply. */
+pub fn send(request: &Request) -> io::Result<Response> {
+    let path = socket_path()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "XDG_RUNTIME_DIR isn't set"))?;
+    send_to(&path, request)
+}
+
+/* Sends request to the daemon listening at path and waits for its reply. */
+pub fn send_to(path: &Path, request: &Request) -> io::Result<Response> {
+    let mut stream = UnixStream::connect(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply)?;
+    serde_json::from_str(&reply).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/* A client that sent a request, waiting for our reply */
+#[derive(Debug)]
+pub struct Client {
+    stream: UnixStream,
+}
+
+impl Client {
+    pub fn reply(mut self, response: &Response) {
+        let mut line = serde_json::to_string(response).unwrap_or_default();
+        line.push('\n');
+        // the client may have given up on us already, there is nothing to do about that
+        let _ = self.stream.write_all(line.as_bytes());
+    }
+}
+
+/* A client we accepted that hasn't sent a whole request yet */
+struct Pending {
+    stream: UnixStream,
+    buf: Vec<u8>,
+    since: Instant,
+}
+
+/* The listening end of the control socket. The socket file is removed when this is dropped. */
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    // clients are read without blocking, so one that is slow to write can't stall the daemon
+    pending: RefCell<Vec<Pending>>,
+}
+
+impl ControlSocket {
+    /* Listens on path. A socket file left behind by a daemon that didn't shut down cleanly is
+     * replaced, but if another daemon is still listening on it, this fails with AddrInUse.
+     * */
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::from(io::ErrorKind::AddrInUse));
+            }
+            fs::remove_file(path)?;
+        }
+
+        // only our own user gets to change the brightness. The umask makes bind create the socket
+        // that way, so nobody else can connect in between bind and set_permissions
+        // SAFETY: umask can't fail, and we put the old one back right after
+        let umask = unsafe { libc::umask(0o077) };
+        let listener = UnixListener::bind(path);
+        unsafe { libc::umask(umask) };
+        let listener = listener?;
+        listener.set_nonblocking(true)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+
+        Ok(ControlSocket {
+            listener,
+            path: path.to_path_buf(),
+            pending: RefCell::new(Vec::new()),
+        })
+    }
+
+    // the listening socket, and every client we are waiting on a request from
+    pub fn fds(&self) -> Vec<RawFd> {
+        let mut fds = vec![self.listener.as_raw_fd()];
+        fds.extend(self.pending.borrow().iter().map(|p| p.stream.as_raw_fd()));
+        fds
+    }
+
+    /* Accepts every waiting client, and returns the requests of those that have sent a whole
+     * line. Nothing here blocks: a client that hasn't finished its request is kept until it does,
+     * or until CLIENT_TIMEOUT passes on clock and we hang up on it.
+     *
+     * Clients that send something we can't parse are answered with an error here, and aren't
+     * returned.
+     * */
+    pub fn read(&self, clock: &impl Clock) -> io::Result<Vec<(Request, Client)>> {
+        let mut pending = self.pending.borrow_mut();
+
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    pending.push(Pending {
+                        stream,
+                        buf: Vec::new(),
+                        since: clock.now(),
+                    });
+                }
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => break,
+                    _ => return Err(e),
+                },
+            }
+        }
+
+        let mut requests = Vec::new();
+        let now = clock.now();
+        for mut client in std::mem::take(&mut *pending) {
+            match read_request(&mut client) {
+                Sent::Line(line) => {
+                    let stream = client.stream;
+                    // replies are written in one go, like send_to expects
+                    let _ = stream.set_nonblocking(false);
+                    match parse_request(&line) {
+                        Ok(request) => requests.push((request, Client { stream })),
+                        Err(e) => Client { stream }.reply(&Response::error(e)),
+                    }
+                }
+                Sent::Partial if now.duration_since(client.since) < CLIENT_TIMEOUT => {
+                    pending.push(client)
+                }
+                // dropping the stream hangs up on it
+                Sent::Partial | Sent::Nothing => {}
+            }
+        }
+
+        Ok(requests)
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// what read_request got from a client
+enum Sent {
+    Line(String),
+    // nothing yet, or not a whole line
+    Partial,
+    // it hung up without sending anything, or sent more than a request
+    Nothing,
+}
+
+// reads whatever a client has sent so far, without blocking
+fn read_request(client: &mut Pending) -> Sent {
+    let mut chunk = [0u8; 512];
+    let hung_up = loop {
+        match client.stream.read(&mut chunk) {
+            Ok(0) => break true,
+            Ok(n) => client.buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break false,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return Sent::Nothing,
+        }
+        if client.buf.contains(&b'\n') {
+            break false;
+        }
+        if client.buf.len() > MAX_REQUEST {
+            return Sent::Nothing;
+        }
+    };
+
+    match client.buf.iter().position(|b| *b == b'\n') {
+        Some(end) => Sent::Line(String::from_utf8_lossy(&client.buf[..end]).into_owned()),
+        // a request doesn't need the newline if the client hangs up after it
+        None if hung_up && !client.buf.is_empty() => {
+            Sent::Line(String::from_utf8_lossy(&client.buf).into_owned())
+        }
+        None if hung_up => Sent::Nothing,
+        None => Sent::Partial,
+    }
+}
+
+pub fn parse_request(line: &str) -> Result<Request, String> {
+    serde_json::from_str(line.trim()).map_err(|e| format!("invalid request: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::clock::manual::ManualClock;
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            parse_request("{\"command\":\"status\"}\n"),
+            Ok(Request::Status)
+        );
+        assert_eq!(parse_request("{\"command\":\"pause\"}"), Ok(Request::Pause));
+        assert_eq!(
+            parse_request("{\"command\":\"set\",\"level\":\"60%\"}"),
+            Ok(Request::Set {
+                level: Level::Percent(60.0)
+            })
+        );
+        assert_eq!(
+            parse_request("{\"command\":\"set\",\"level\":120}"),
+            Ok(Request::Set {
+                level: Level::Raw(120)
+            })
+        );
+
+        assert!(parse_request("{\"command\":\"set\"}").is_err());
+        assert!(parse_request("{\"command\":\"dim\"}").is_err());
+        assert!(parse_request("status").is_err());
+    }
+
+    #[test]
+    fn test_request_round_trip() {
+        let request = Request::Set {
+            level: Level::Percent(60.0),
+        };
+        let line = serde_json::to_string(&request).unwrap();
+        assert_eq!(line, "{\"command\":\"set\",\"level\":\"60%\"}");
+        assert_eq!(parse_request(&line), Ok(request));
+    }
+
+    #[test]
+    fn test_response_json() {
+        assert_eq!(
+            serde_json::to_string(&Response::ok()).unwrap(),
+            "{\"ok\":true}"
+        );
+        assert_eq!(
+            serde_json::to_string(&Response::error("no".to_string())).unwrap(),
+            "{\"ok\":false,\"error\":\"no\"}"
+        );
+    }
+
+    #[test]
+    fn test_control_socket() {
+        let clock = ManualClock::new();
+        let path =
+            std::env::temp_dir().join(format!("gamma_daemon_control_{}.sock", std::process::id()));
+        let socket = ControlSocket::open(&path).unwrap();
+        assert!(socket.read(&clock).unwrap().is_empty());
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        // a second daemon can't take over the socket
+        assert_eq!(
+            ControlSocket::open(&path).err().map(|e| e.kind()),
+            Some(io::ErrorKind::AddrInUse)
+        );
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"{\"command\":\"status\"}\n").unwrap();
+        let mut requests = socket.read(&clock).unwrap();
+        assert_eq!(requests.len(), 1);
+        let (request, waiting) = requests.pop().unwrap();
+        assert_eq!(request, Request::Status);
+
+        waiting.reply(&Response::ok());
+        let mut reply = String::new();
+        BufReader::new(&client).read_line(&mut reply).unwrap();
+        assert_eq!(reply, "{\"ok\":true}\n");
+
+        // garbage is answered with an error instead of being passed on
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"dim please\n").unwrap();
+        assert!(socket.read(&clock).unwrap().is_empty());
+        let mut reply = String::new();
+        BufReader::new(&client).read_line(&mut reply).unwrap();
+        assert!(reply.starts_with("{\"ok\":false"));
+
+        // a client that is slow to send its request doesn't hold up the others
+        let mut slow = UnixStream::connect(&path).unwrap();
+        slow.write_all(b"{\"command\":").unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"{\"command\":\"pause\"}\n").unwrap();
+        let requests: Vec<Request> = socket
+            .read(&clock)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.0)
+            .collect();
+        assert_eq!(requests, vec![Request::Pause]);
+        assert_eq!(socket.fds().len(), 2);
+        slow.write_all(b"\"resume\"}\n").unwrap();
+        let requests: Vec<Request> = socket
+            .read(&clock)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.0)
+            .collect();
+        assert_eq!(requests, vec![Request::Resume]);
+        assert_eq!(socket.fds().len(), 1);
+
+        // one that sends nothing is hung up on once CLIENT_TIMEOUT passes
+        let silent = UnixStream::connect(&path).unwrap();
+        assert!(socket.read(&clock).unwrap().is_empty());
+        clock.sleep(CLIENT_TIMEOUT / 2);
+        assert!(socket.read(&clock).unwrap().is_empty());
+        assert_eq!(socket.fds().len(), 2);
+        clock.sleep(CLIENT_TIMEOUT);
+        assert!(socket.read(&clock).unwrap().is_empty());
+        assert_eq!(socket.fds().len(), 1);
+        let mut reply = String::new();
+        assert_eq!(BufReader::new(&silent).read_line(&mut reply).unwrap(), 0);
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,198 @@
+/*  Contains the Events struct, which waits on everything that can wake the daemon up:
+ *  kernel uevents, changes to the config file, signals (SIGHUP, SIGTERM and SIGINT), and
+ *  requests on the control socket.
+ *
+ *  Any of these can be missing (i.e: no permission to open the uevent socket, or the config
+ *  directory doesn't exist), in which case we just don't wait on it.
+ *
+ * */
+
+use crate::daemon::clock::Clock;
+use crate::daemon::config::watch::ConfigWatch;
+use crate::daemon::control::{Client, ControlSocket, Request};
+use crate::daemon::signals::SignalFd;
+use crate::daemon::uevent::{Uevent, UeventSocket};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/* Something that happened while we were waiting */
+#[derive(Debug)]
+pub enum Event {
+    // a power_supply or backlight device changed
+    Device(Uevent),
+    // the config file changed, or we were sent SIGHUP
+    Reload,
+    // we were sent SIGTERM or SIGINT
+    Quit,
+    // a request on the control socket, the client is waiting for a reply
+    Command(Request, Client),
+}
+
+pub struct Events {
+    uevents: Option<UeventSocket>,
+    config_watch: Option<ConfigWatch>,
+    signals: Option<SignalFd>,
+    control: Option<ControlSocket>,
+}
+
+impl Events {
+    /* Opens every event source it can, printing a message for the ones it can't.
+     * config_files are watched for changes, and commands are read from control_socket.
+     * */
+    pub fn open(config_files: &[PathBuf], control_socket: Option<&Path>) -> Self {
+        let uevents = match UeventSocket::open() {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                eprintln!(
+                    "Can't listen for uevents ({}), gamma_daemon will check for changes every second",
+                    e
+                );
+                None
+            }
+        };
+
+        let config_watch = match ConfigWatch::open(config_files) {
+            Ok(watch) => Some(watch),
+            Err(e) => {
+                eprintln!(
+                    "Can't watch the config files for changes ({}), send SIGHUP to reload them",
+                    e
+                );
+                None
+            }
+        };
+
+        let signals = match SignalFd::open(&[libc::SIGHUP, libc::SIGTERM, libc::SIGINT]) {
+            Ok(signals) => Some(signals),
+            Err(e) => {
+                eprintln!("Can't listen for signals: {}", e);
+                None
+            }
+        };
+
+        let control = match control_socket {
+            Some(path) => match ControlSocket::open(path) {
+                Ok(control) => Some(control),
+                Err(e) => {
+                    eprintln!("Can't open the control socket {}: {}", path.display(), e);
+                    None
+                }
+            },
+            None => {
+                eprintln!("XDG_RUNTIME_DIR isn't set, gamma_daemon won't open a control socket");
+                None
+            }
+        };
+
+        Events {
+            uevents,
+            config_watch,
+            signals,
+            control,
+        }
+    }
+
+    // waits on nothing, so only the clock moves, i.e: in a replay of a trace
+    pub fn none() -> Self {
+        Events {
+            uevents: None,
+            config_watch: None,
+            signals: None,
+            control: None,
+        }
+    }
+
+    // true if we hear about device changes as they happen, so we don't need to poll for them
+    pub fn has_uevents(&self) -> bool {
+        self.uevents.is_some()
+    }
+
+    /* Waits up to timeout for something to happen, and returns what did.
+     * Returns an empty Vec if the timeout passed without anything happening.
+     * Clients on the control socket are timed by clock, see ControlSocket::read.
+     * */
+    pub fn wait(&self, clock: &impl Clock, timeout: Duration) -> io::Result<Vec<Event>> {
+        let mut fds: Vec<libc::c_int> = [
+            self.uevents.as_ref().map(|u| u.fd()),
+            self.config_watch.as_ref().map(|w| w.fd()),
+            self.signals.as_ref().map(|s| s.fd()),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        // the control socket, and the clients on it we are waiting on
+        if let Some(control) = &self.control {
+            fds.extend(control.fds());
+        }
+
+        if fds.is_empty() {
+            thread::sleep(timeout);
+            return Ok(Vec::new());
+        }
+
+        let mut pfds: Vec<libc::pollfd> = fds
+            .iter()
+            .map(|fd| libc::pollfd {
+                fd: *fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+
+        let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+        // SAFETY: pfds is valid for pfds.len() entries for the whole call
+        let ready =
+            unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, timeout_ms) };
+        if ready < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::Interrupted => Ok(Vec::new()),
+                _ => Err(e),
+            };
+        }
+        if ready == 0 {
+            return Ok(Vec::new());
+        }
+
+        self.read(clock)
+    }
+
+    // reads whatever is waiting on each source
+    fn read(&self, clock: &impl Clock) -> io::Result<Vec<Event>> {
+        let mut events = Vec::new();
+
+        if let Some(uevents) = &self.uevents {
+            events.extend(uevents.read()?.into_iter().map(Event::Device));
+        }
+
+        let mut reload = false;
+        let mut quit = false;
+        if let Some(watch) = &self.config_watch {
+            reload |= watch.changed()?;
+        }
+        if let Some(signals) = &self.signals {
+            let signals = signals.read()?;
+            reload |= signals.contains(&libc::SIGHUP);
+            quit |= signals.contains(&libc::SIGTERM) || signals.contains(&libc::SIGINT);
+        }
+        if reload {
+            events.push(Event::Reload);
+        }
+        if quit {
+            events.push(Event::Quit);
+        }
+
+        if let Some(control) = &self.control {
+            events.extend(
+                control
+                    .read(clock)?
+                    .into_iter()
+                    .map(|(request, client)| Event::Command(request, client)),
+            );
+        }
+
+        Ok(events)
+    }
+}
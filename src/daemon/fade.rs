@@ -0,0 +1,379 @@
+/*  Contains the fade engine. Instead of jumping straight to a new brightness, a Fader steps a
+ *  Backlight from its current brightness to the target over the duration set in the config's
+ *  [fade] section, following an easing curve.
+ *
+ *  Fades are driven by the daemon loop calling tick(), so a new target arriving mid-fade simply
+ *  replaces the running fade, starting from wherever the old one got to.
+ *
+ * */
+
+use super::clock::Clock;
+use super::{Backlight, BacklightError};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+// How often a running fade writes a new brightness
+pub const FADE_STEP: Duration = Duration::from_millis(25);
+
+// Shape of a fade, set with easing = "..." in the [fade] section
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Easing {
+    #[default]
+    Linear,
+    // fast at first, slowing down near the target
+    EaseOut,
+    // steps evenly in log space, which looks even to the eye since we perceive brightness
+    // logarithmically
+    #[serde(alias = "log")]
+    Perceptual,
+}
+
+impl Easing {
+    /* Returns the brightness at progress t (0.0 to 1.0) of a fade from -> to */
+    fn interpolate(&self, from: u32, to: u32, t: f32) -> u32 {
+        let (from_f, to_f) = (from as f32, to as f32);
+
+        let value = match self {
+            Easing::Linear => from_f + (to_f - from_f) * t,
+            Easing::EaseOut => from_f + (to_f - from_f) * (1.0 - (1.0 - t).powi(3)),
+            Easing::Perceptual => {
+                // +1 so a fade from or to 0 still works
+                let (log_from, log_to) = ((from_f + 1.0).ln(), (to_f + 1.0).ln());
+                (log_from + (log_to - log_from) * t).exp() - 1.0
+            }
+        };
+
+        value.round().max(0.0) as u32
+    }
+}
+
+/* A single fade from one brightness to another */
+#[derive(Debug, Clone, Copy)]
+pub struct Fade {
+    from: u32,
+    to: u32,
+    start: Instant,
+    duration: Duration,
+    easing: Easing,
+}
+
+impl Fade {
+    /* Returns the brightness the fade is at by the time now */
+    pub fn level_at(&self, now: Instant) -> u32 {
+        if self.done(now) {
+            return self.to;
+        }
+
+        let t = now.duration_since(self.start).as_secs_f32() / self.duration.as_secs_f32();
+        self.easing.interpolate(self.from, self.to, t)
+    }
+
+    pub fn done(&self, now: Instant) -> bool {
+        now.duration_since(self.start) >= self.duration
+    }
+}
+
+/* Wraps a Backlight device and the fade currently running on it, if any */
+pub struct Fader<T: Backlight> {
+    pub device: T,
+    fade: Option<Fade>,
+    current: Option<u32>, // last brightness we wrote
+    target: Option<u32>,  // brightness the last fade was going to
+    settled: Option<u32>, // actual_brightness once the last fade finished
+    // when we noticed someone else changing the brightness, automatic changes are paused until
+    // the override is cleared
+    overridden: Option<Instant>,
+}
+
+impl<T: Backlight> Fader<T> {
+    pub fn new(device: T) -> Self {
+        Fader {
+            device,
+            fade: None,
+            current: None,
+            target: None,
+            settled: None,
+            overridden: None,
+        }
+    }
+
+    /* Starts fading the device to target.
+     *
+     * If a fade is already running it is cancelled, and the new one starts from the brightness the
+     * old one had reached. A zero duration changes the brightness right away.
+     *
+     * Returns an error without starting a fade if target is above the device's max brightness.
+     * */
+    pub fn fade_to(
+        &mut self,
+        target: u32,
+        duration: Duration,
+        easing: Easing,
+        now: Instant,
+    ) -> Result<(), BacklightError> {
+        let max = self.device.max_brightness();
+        if target > max {
+            return Err(BacklightError::OutOfRange { given: target, max });
+        }
+
+        let from = match (self.fade, self.current) {
+            (Some(fade), _) => fade.level_at(now),
+            (None, Some(current)) => current,
+            (None, None) => self.device.brightness()?,
+        };
+        self.current = Some(from); // the device is already here, no need to write it again
+        self.target = Some(target);
+
+        self.fade = Some(Fade {
+            from,
+            to: target,
+            start: now,
+            duration,
+            easing,
+        });
+
+        self.tick(now).map(|_| ())
+    }
+
+    /* Writes the brightness the running fade should be at by now.
+     * Returns true if the fade is still running afterwards.
+     * */
+    pub fn tick(&mut self, now: Instant) -> Result<bool, BacklightError> {
+        let fade = match self.fade {
+            Some(fade) => fade,
+            None => return Ok(false),
+        };
+
+        let level = fade.level_at(now);
+        let done = fade.done(now);
+        if done {
+            self.fade = None;
+        }
+
+        if self.current != Some(level) {
+            if let Err(e) = self.device.change_gamma(level) {
+                self.fade = None;
+                return Err(e);
+            }
+            self.current = Some(level);
+        }
+
+        // drivers can round what we write, so remember what the hardware actually ended up at
+        if done {
+            self.settled = self.device.actual_brightness().ok();
+        }
+
+        Ok(self.fade.is_some())
+    }
+
+    /* Runs the current fade to the end, sleeping on clock until it's done */
+    pub fn finish(&mut self, clock: &impl Clock) -> Result<(), BacklightError> {
+        while self.tick(clock.now())? {
+            clock.sleep(FADE_STEP);
+        }
+        Ok(())
+    }
+
+    pub fn is_fading(&self) -> bool {
+        self.fade.is_some()
+    }
+
+    // the brightness we last asked this device to go to, even if the fade there hasn't finished
+    pub fn target(&self) -> Option<u32> {
+        self.target
+    }
+
+    /* Checks whether something other than us changed the brightness since our last fade finished,
+     * i.e: the user pressed the brightness keys. If so, the device is marked as overridden.
+     *
+     * Returns true only when this call is the one that noticed the override.
+     * */
+    pub fn check_override(&mut self, now: Instant) -> Result<bool, BacklightError> {
+        if self.overridden.is_some() || self.is_fading() {
+            return Ok(false);
+        }
+        let settled = match self.settled {
+            Some(settled) => settled,
+            None => return Ok(false),
+        };
+
+        if self.device.actual_brightness()? == settled {
+            return Ok(false);
+        }
+        self.overridden = Some(now);
+        Ok(true)
+    }
+
+    // when the user took over the brightness, if they have
+    pub fn overridden_since(&self) -> Option<Instant> {
+        self.overridden
+    }
+
+    // treats the brightness as set by the user from now on, i.e: when it was set over the control socket
+    pub fn set_override(&mut self, now: Instant) {
+        self.overridden = Some(now);
+    }
+
+    /* Hands the brightness back to the daemon. The next fade starts from wherever the user left
+     * the device, rather than from what we last wrote.
+     * */
+    pub fn clear_override(&mut self) {
+        self.overridden = None;
+        self.current = None;
+        self.settled = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    struct MockBacklight {
+        start: u32,
+        writes: RefCell<Vec<u32>>,
+        // brightness someone else set, i.e: with the brightness keys
+        by_hand: RefCell<Option<u32>>,
+    }
+
+    impl Backlight for MockBacklight {
+        fn name(&self) -> &str {
+            "mock_backlight"
+        }
+
+        fn max_brightness(&self) -> u32 {
+            1000
+        }
+
+        fn brightness(&self) -> Result<u32, BacklightError> {
+            match *self.by_hand.borrow() {
+                Some(level) => Ok(level),
+                None => Ok(*self.writes.borrow().last().unwrap_or(&self.start)),
+            }
+        }
+
+        fn actual_brightness(&self) -> Result<u32, BacklightError> {
+            self.brightness()
+        }
+
+        fn change_gamma(&self, gamma: u32) -> Result<(), BacklightError> {
+            self.by_hand.replace(None);
+            self.writes.borrow_mut().push(gamma);
+            Ok(())
+        }
+    }
+
+    fn mock_fader(start: u32) -> Fader<MockBacklight> {
+        Fader::new(MockBacklight {
+            start,
+            writes: RefCell::new(Vec::new()),
+            by_hand: RefCell::new(None),
+        })
+    }
+
+    #[test]
+    fn test_instant_change() {
+        let mut fader = mock_fader(500);
+        let now = Instant::now();
+
+        fader
+            .fade_to(200, Duration::ZERO, Easing::Linear, now)
+            .unwrap();
+
+        assert!(!fader.is_fading());
+        assert_eq!(*fader.device.writes.borrow(), vec![200]);
+    }
+
+    #[test]
+    fn test_linear_fade() {
+        let mut fader = mock_fader(0);
+        let start = Instant::now();
+
+        fader
+            .fade_to(1000, Duration::from_secs(1), Easing::Linear, start)
+            .unwrap();
+        assert!(fader.is_fading());
+
+        assert!(fader.tick(start + Duration::from_millis(250)).unwrap());
+        assert!(fader.tick(start + Duration::from_millis(500)).unwrap());
+        assert!(!fader.tick(start + Duration::from_millis(1200)).unwrap());
+
+        assert_eq!(*fader.device.writes.borrow(), vec![250, 500, 1000]);
+        assert!(!fader.is_fading());
+    }
+
+    #[test]
+    fn test_fade_cancelled_by_new_target() {
+        let mut fader = mock_fader(0);
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+
+        fader.fade_to(1000, second, Easing::Linear, start).unwrap();
+        fader.tick(start + Duration::from_millis(500)).unwrap();
+
+        // the new fade starts where the old one got to
+        let half = start + Duration::from_millis(500);
+        fader.fade_to(0, second, Easing::Linear, half).unwrap();
+        fader.tick(half + Duration::from_millis(500)).unwrap();
+        fader.tick(half + second).unwrap();
+
+        assert_eq!(*fader.device.writes.borrow(), vec![500, 250, 0]);
+    }
+
+    #[test]
+    fn test_fade_above_max() {
+        let mut fader = mock_fader(0);
+        let result = fader.fade_to(1001, Duration::ZERO, Easing::Linear, Instant::now());
+
+        assert!(result.is_err());
+        assert!(!fader.is_fading());
+        assert!(fader.device.writes.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_easing_curves() {
+        assert_eq!(Easing::Linear.interpolate(100, 200, 0.5), 150);
+        assert_eq!(Easing::EaseOut.interpolate(100, 200, 0.5), 188);
+        assert_eq!(Easing::Perceptual.interpolate(0, 999, 0.5), 31);
+        assert_eq!(Easing::Perceptual.interpolate(999, 0, 1.0), 0);
+
+        for easing in [Easing::Linear, Easing::EaseOut, Easing::Perceptual] {
+            assert_eq!(easing.interpolate(40, 900, 0.0), 40);
+            assert_eq!(easing.interpolate(40, 900, 1.0), 900);
+        }
+    }
+
+    #[test]
+    fn test_manual_override() {
+        let mut fader = mock_fader(500);
+        let start = Instant::now();
+
+        // nothing we set yet, so nothing to compare against
+        assert!(!fader.check_override(start).unwrap());
+
+        fader
+            .fade_to(200, Duration::from_secs(1), Easing::Linear, start)
+            .unwrap();
+        // mid-fade the brightness is meant to be moving
+        assert!(!fader.check_override(start).unwrap());
+        fader.tick(start + Duration::from_secs(1)).unwrap();
+        assert!(!fader.check_override(start).unwrap());
+
+        // the user pressed the brightness keys
+        fader.device.by_hand.replace(Some(700));
+        let later = start + Duration::from_secs(5);
+        assert!(fader.check_override(later).unwrap());
+        assert!(!fader.check_override(later).unwrap());
+        assert_eq!(fader.overridden_since(), Some(later));
+
+        // once cleared, the next fade starts from where the user left it
+        fader.clear_override();
+        assert_eq!(fader.overridden_since(), None);
+        fader
+            .fade_to(200, Duration::from_secs(1), Easing::Linear, later)
+            .unwrap();
+        fader.tick(later + Duration::from_millis(500)).unwrap();
+        assert_eq!(*fader.device.writes.borrow(), vec![200, 450]);
+    }
+}
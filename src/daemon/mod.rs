@@ -1,248 +1,962 @@
 /* This contains the daemon functionality
  *
  * Only the run function is public, since the client code doesn't need to
- * do any of the gamma calculations or changes, that's all abstracted away in this file
+ * do any of the gamma calculations or changes, that's all abstracted away in this file.
+ * The config and control modules are public too, so the client subcommands can check a config
+ * file and talk to a running daemon.
  *
  */
 
-use battery::{Battery, State};
-use bulbb::error::Error;
-use bulbb::monitor::MonitorDevice;
+use battery::State;
 use daemonize::Daemonize;
+use std::fmt;
 use std::fs::File;
-use std::thread;
-use std::time::Duration;
-mod config;
+use std::io::{self, Write};
+use std::num::ParseIntError;
+use std::time::{Duration, Instant};
+pub mod batteries;
+pub mod clock;
+pub mod config;
+pub mod control;
+mod events;
+mod fade;
+mod notify;
+mod policy;
+mod power_supply;
 mod read_file;
-use crate::daemon::config::Config;
-use std::env;
-
-pub const AC_STATUS_FILE: &str = "/sys/class/power_supply/AC/online"; //this is the AC status file
+mod signals;
+pub mod sysfs;
+pub mod trace;
+mod uevent;
+use crate::daemon::batteries::{Batteries, Reading};
+use crate::daemon::clock::Clock;
+use crate::daemon::config::rules::Target;
+use crate::daemon::config::{Config, Level};
+use crate::daemon::control::{DeviceStatus, Request, Response, Status};
+use crate::daemon::events::{Event, Events};
+use crate::daemon::fade::{Fader, FADE_STEP};
+use crate::daemon::notify::Notifier;
+use crate::daemon::policy::{Action, Mode, Policy, Transition};
+use crate::daemon::trace::Recorder;
+use crate::error;
+use std::path::{Path, PathBuf};
+
+// how many loops in a row the batteries can fail to be read before we give up
+const BATTERY_RETRIES: u32 = 10;
+// how long to wait between those tries
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+// the group daemonize() switches to, which the udev rules give write access to the backlights
+pub const GROUP: &str = "video";
 
 pub trait Backlight {
-    fn change_gamma(&self, gamma: u32) -> Result<(), Error>;
+    // sysfs name of the device, used to pick its section in the config file
+    fn name(&self) -> &str;
+    // percentages in the config are scaled against this
+    fn max_brightness(&self) -> u32;
+    // the brightness the device is at right now, fades start from here
+    fn brightness(&self) -> Result<u32, BacklightError>;
+    // the brightness the hardware reports, used to notice someone else changing it
+    fn actual_brightness(&self) -> Result<u32, BacklightError>;
+    fn change_gamma(&self, gamma: u32) -> Result<(), BacklightError>;
+}
+
+// Why a backlight couldn't be read or changed
+#[derive(Debug)]
+pub enum BacklightError {
+    // a brightness above the device's max_brightness
+    OutOfRange { given: u32, max: u32 },
+    Io(io::Error),
+    // a sysfs file that should hold a number doesn't
+    Parse(ParseIntError),
+}
+
+impl fmt::Display for BacklightError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BacklightError::OutOfRange { given, max } => write!(
+                f,
+                "brightness {} is above the max brightness ({})",
+                given, max
+            ),
+            BacklightError::Io(e) => write!(f, "{}", e),
+            BacklightError::Parse(e) => write!(f, "unexpected brightness: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BacklightError {}
+
+impl From<io::Error> for BacklightError {
+    fn from(e: io::Error) -> Self {
+        BacklightError::Io(e)
+    }
 }
 
-impl Backlight for MonitorDevice {
-    fn change_gamma(&self, gamma: u32) -> Result<(), Error> {
-        self.set_brightness(gamma)
+impl From<ParseIntError> for BacklightError {
+    fn from(e: ParseIntError) -> Self {
+        BacklightError::Parse(e)
     }
 }
 
+/* Where the batteries are read from, i.e: the battery crate or sysfs */
+pub trait BatterySource {
+    // looks for the batteries again, i.e: after one was pulled out or put back in
+    fn scan(&mut self) -> Result<(), battery::Error>;
+    // reads fresh values for the batteries the last scan found
+    fn refresh(&mut self) -> Result<(), battery::Error>;
+    // the values of each battery, as of the last refresh
+    fn readings(&self) -> Vec<Reading>;
+}
+
+/* Where the AC supplies are read from. pinned is the supply the config names, if it does. */
+pub trait PowerSource {
+    // the names of the AC supplies, only the pinned one if it's set
+    fn supplies(&self, pinned: Option<&str>) -> io::Result<Vec<String>>;
+    // true if any AC supply is online
+    fn ac_online(&self, pinned: Option<&str>) -> bool;
+}
+
 /* struct to bundle useful information about the notebooks battery
  * and AC charger.
  *
- * We'll use this to check if the battery status changed or if the AC is plugged in
+ * The Policy in policy.rs uses this to check if the battery status changed or if the AC is plugged in
  */
 #[derive(Debug)]
 pub struct BatteryInfo {
     soc: f32,
-    old_status: State,
     new_status: State,
-    old_ac_status: char,
     new_ac_status: char,
     gamma_values: Box<Config>,
+    // what the daemon decided from the readings so far, see policy.rs
+    policy: Policy,
 }
 
 // Make a struct for our Battery Info
 // Initially sets all values to either unknown and 0 for the state and AC status
 // These will be updated during the Daemons run time
-fn new_battery_info(gamma_values: Config, battery: &mut Battery) -> BatteryInfo {
+fn new_battery_info<B: BatterySource>(
+    gamma_values: Config,
+    batteries: &Batteries<B>,
+) -> BatteryInfo {
     BatteryInfo {
-        soc: battery.state_of_charge().value,
-        old_status: State::Unknown,
+        soc: batteries.state_of_charge(),
         new_status: State::Unknown,
-        old_ac_status: '0',
         new_ac_status: '0',
         gamma_values: Box::new(gamma_values),
+        policy: Policy::default(),
     }
 }
-// updates old status variables so we can compare them in the next iteration of the program loop
+// updates the state of charge, the state and AC are read at the top of every loop
 // Assumes new_battery_info() has been called by the client code.
-fn update(info: &mut BatteryInfo, battery: &Battery) {
-    info.old_status = info.new_status;
-    info.old_ac_status = info.new_ac_status;
-    info.soc = battery.state_of_charge().value;
+fn update<B: BatterySource>(info: &mut BatteryInfo, batteries: &Batteries<B>) {
+    info.soc = batteries.state_of_charge();
 }
 
 /* Helper function to determine the gamma if the battery is discharging and/or is low.
  *
- * If the battery is discharging and isnt below the threshold set by the user, then
+ * If the user set a [curve], the gamma comes from the curve at the battery's current charge.
+ * Otherwise, if the battery is discharging and isnt below the threshold set by the user, then
  * the function returns the user's 'discharging' gamma setting. Otherwise the function returns
- * the 'low' gamma setting.
+ * the 'low' gamma setting. Once the charge has been at or below the threshold, it stays low until
+ * the battery's state or the AC changes, like the Policy's Low phase.
  *
  * */
-fn low_or_discharging(info: &BatteryInfo) -> u32 {
-    let config = &info.gamma_values;
-    if info.soc <= (config.low_perc as f32) / 100.0 {
-        return config.low;
+fn low_or_discharging(info: &BatteryInfo, config: &Config, max_brightness: u32) -> u32 {
+    if let Some(curve) = &config.curve {
+        return curve.brightness_at(info.soc, max_brightness);
+    }
+    if info.soc.min(info.policy.lowest) <= (config.low_perc as f32) / 100.0 {
+        return config.low.resolve(max_brightness);
     }
-    config.discharging
+    config.discharging.resolve(max_brightness)
 }
 
-/*  Returns a u32 for the new brightness we shall set for the MonitorDevice
+/*  Returns a u32 for the new brightness we shall set for the Backlight
  *
- *  This function requires  reference to the battery's state, and a reference to a battery info struct
+ *  This function requires  reference to the battery's state, and a reference to a battery info struct,
+ *  the brightness table of the device (see Config::for_device), and the device's max brightness
  *
- *  Depending on the state of the battery and the AC, this function will set the gamma to the matching value mapped to the state in
- *  the config file
+ *  Depending on the state of the battery and the AC, this function will set the gamma to the value
+ *  the config's [rules] pick for that state. Percentages in the config are scaled against max_brightness.
  *
  * */
-fn calc_new_brightness(info: &BatteryInfo) -> u32 {
-    let config = &info.gamma_values; // user config values
-    let state = info.new_status;
-    let plugged = info.new_ac_status == '1';
-
-    // calculate gamma based on the battery state
-    match (state, plugged) {
-        (State::Full, false) => config.full,
-        (State::Full, true) => config.full,
-        (State::Charging, _) => config.charging,
-        (State::Discharging, _) => low_or_discharging(info),
-        (State::Empty, _) => low_or_discharging(info),
-        (State::Unknown, true) => config.ac_in,
-        (State::Unknown, false) => config.discharging,
-        _ => config.discharging,
-    }
-}
-
-/* Returns a bool showing if the battery has changed states.
- * I.E: From State::Charging to State::Discharging
- *
- * This function requires references a battery state and a bool.
+fn calc_new_brightness(info: &BatteryInfo, config: &Config, max_brightness: u32) -> u32 {
+    let target = config
+        .rules
+        .target(info.new_status, info.new_ac_status == '1');
+
+    match target.level(config) {
+        Some(level) => level.resolve(max_brightness),
+        None => low_or_discharging(info, config, max_brightness),
+    }
+}
+
+// true if the brightness follows the battery's charge right now, see Target::Draining
+fn draining(info: &BatteryInfo) -> bool {
+    let target = info
+        .gamma_values
+        .rules
+        .target(info.new_status, info.new_ac_status == '1');
+    target == Target::Draining
+}
+
+/* Returns true if a [curve] moved the gamma of any device away from what we last set it to.
  *
- * The battery state has our state values, the bool is a check to make
- * sure if we set the screen gamma to low, the status changes only if the screen wasn't
- * at low before hand. If its still low, then the status hasn't changed
+ * Curves only apply while the rules say draining, but unlike the other config values they change
+ * as the battery drains, so we check them on every state of charge update, not just when the
+ * state changes.
+ * */
+fn curve_moved<T: Backlight>(faders: &[Fader<T>], info: &BatteryInfo) -> bool {
+    if !draining(info) {
+        return false;
+    }
+
+    faders
+        .iter()
+        .filter(|f| f.overridden_since().is_none())
+        .any(|fader| {
+            let config = info.gamma_values.for_device(fader.device.name());
+            config.curve.is_some()
+                && fader.target()
+                    != Some(calc_new_brightness(
+                        info,
+                        &config,
+                        fader.device.max_brightness(),
+                    ))
+        })
+}
+
+/* Looks for devices whose brightness was changed by someone else, i.e: the brightness keys, and
+ * stops changing them automatically. Overrides older than the config's override_timeout are
+ * handed back to the daemon.
  *
- */
-fn status_changed(status: &BatteryInfo, low_set: &bool) -> bool {
-    let old_status = status.old_status;
-    let new_status = status.new_status;
+ * Returns true if any override ran out, so the brightness should be set again.
+ * */
+fn check_overrides<T: Backlight>(faders: &mut [Fader<T>], config: &Config, now: Instant) -> bool {
+    let mut expired = false;
+
+    for fader in faders.iter_mut() {
+        match fader.check_override(now) {
+            Ok(true) => println!(
+                "Brightness of {} was changed by hand, leaving it alone",
+                fader.device.name()
+            ),
+            Ok(false) => {}
+            Err(e) => println!("Error reading gamma of {}: {}", fader.device.name(), e),
+        }
 
-    let config = &status.gamma_values;
-    let low_perc = (config.low_perc as f32) / 100.0;
-    let curr_perc = status.soc;
+        if let (Some(since), Some(timeout)) = (fader.overridden_since(), config.override_timeout())
+        {
+            if now.duration_since(since) >= timeout {
+                fader.clear_override();
+                expired = true;
+            }
+        }
+    }
+
+    expired
+}
 
-    let old_ac_status = status.old_ac_status;
-    let new_ac_status = status.new_ac_status;
+// hands every device back to the daemon
+fn clear_overrides<T: Backlight>(faders: &mut [Fader<T>]) {
+    for fader in faders.iter_mut() {
+        fader.clear_override();
+    }
+}
 
-    old_status != new_status
-        || old_ac_status != new_ac_status
-        || (curr_perc < low_perc && !low_set/*have we already set the gamma to low?*/)
+// how long until the first override runs out, if any will
+fn next_override_expiry<T: Backlight>(
+    faders: &[Fader<T>],
+    config: &Config,
+    now: Instant,
+) -> Option<Duration> {
+    let timeout = config.override_timeout()?;
+
+    faders
+        .iter()
+        .filter_map(|f| f.overridden_since())
+        .map(|since| (since + timeout).saturating_duration_since(now))
+        .min()
 }
 
-fn daemonize() {
-    let stdout = File::create("/tmp/gamma_daemon.out").unwrap();
-    let stderr = File::create("/tmp/gamma_daemon.err").unwrap();
+fn daemonize() -> Result<(), error::Error> {
+    let log = |path: &str| {
+        File::create(path).map_err(|e| error::Error::Daemonize(format!("{}: {}", path, e)))
+    };
+    let stdout = log("/tmp/gamma_daemon.out")?;
+    let stderr = log("/tmp/gamma_daemon.err")?;
 
     let daemonize = Daemonize::new()
         .pid_file("/tmp/gamma_daemon.pid")
         .working_directory("/tmp")
-        .group("video") // Group name
+        .group(GROUP) // Group name
         .stdout(stdout) // Redirect stdout to `/tmp/daemon.out`.
         .stderr(stderr) // Redirect stderr to `/tmp/daemon.err`.
         .privileged_action(|| "Executed before drop privileges");
 
     match daemonize.start() {
-        Ok(_) => println!("gamma_daemon started"),
-        Err(e) => eprintln!("{}", e),
+        Ok(_) => {
+            println!("gamma_daemon started");
+            Ok(())
+        }
+        Err(e) => Err(error::Error::Daemonize(e.to_string())),
     }
 }
 
-/* Updates important structs and sleeps the thread.
+/* In the foreground, everything we print goes to stderr, which is where systemd and container
+ * runtimes collect logs from.
+ * */
+pub fn log_to_stderr() {
+    // SAFETY: dup2 on the standard fds, which stay open for the whole life of the process
+    unsafe {
+        libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO);
+    }
+}
+
+/* Runs f with everything the daemon prints going to stderr, i.e: while a trace is replayed, whose
+ * decisions are what goes to stdout. stdout is put back afterwards.
+ * */
+pub fn with_log_on_stderr<R>(f: impl FnOnce() -> R) -> R {
+    let _ = io::stdout().flush();
+    // SAFETY: dup and dup2 on the standard fds, and saved is ours to close
+    let saved = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if saved < 0 {
+        return f();
+    }
+    unsafe {
+        libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO);
+    }
+
+    let result = f();
+
+    let _ = io::stdout().flush();
+    unsafe {
+        libc::dup2(saved, libc::STDOUT_FILENO);
+        libc::close(saved);
+    }
+    result
+}
+
+/* Sleeps the thread and updates important structs.
  * This shall be called each loop during the daemons run time
+ *
+ * If the batteries can't be read, i.e: one disappeared while the notebook resumed from suspend,
+ * the last values we read are kept and failures is counted up. Once it reaches BATTERY_RETRIES
+ * the error is returned. A successful read sets it back to 0.
+ *
+ * Returns whatever woke us up early, if anything
  * */
-fn loop_update(
-    manager: &battery::Manager,
+fn loop_update<T: Backlight, B: BatterySource>(
+    clock: &impl Clock,
     battery_info: &mut BatteryInfo,
-    battery: &mut Battery,
+    batteries: &mut Batteries<B>,
+    faders: &mut [Fader<T>],
+    events: &Events,
+    sleep_duration: Duration,
+    failures: &mut u32,
+) -> Result<Vec<Event>, error::Error> {
+    let woken_by = wait_and_fade(clock, faders, events, sleep_duration);
+
+    match batteries.refresh().map_err(error::Error::Battery) {
+        Ok(_) => *failures = 0,
+        Err(e) if e.is_transient() && *failures < BATTERY_RETRIES => {
+            *failures += 1;
+            eprintln!("{}, trying again ({}/{})", e, failures, BATTERY_RETRIES);
+        }
+        Err(e) => return Err(e),
+    }
+
+    update(battery_info, batteries);
+    Ok(woken_by)
+}
+
+/* Sleeps for up to sleep_duration, stepping any running fades while we wait.
+ *
+ * This returns as soon as something happens, i.e: a power_supply changes, so plugging in the
+ * charger is handled right away, a backlight comes or goes, or the config file changes. The
+ * uevents a backlight sends when its brightness is written, i.e: by a fade, are waited through,
+ * see Uevent::wakes. If nothing happens, it sleeps for the whole sleep_duration and returns an
+ * empty Vec.
+ * */
+fn wait_and_fade<T: Backlight>(
+    clock: &impl Clock,
+    faders: &mut [Fader<T>],
+    events: &Events,
     sleep_duration: Duration,
-) -> Result<(), battery::Error> {
-    update(battery_info, battery);
-    manager.refresh(battery)?;
-    thread::sleep(sleep_duration);
-    Ok(())
+) -> Vec<Event> {
+    let wake = clock.now() + sleep_duration;
+
+    loop {
+        let now = clock.now();
+        if now >= wake {
+            return Vec::new();
+        }
+
+        let fading = faders.iter().any(|f| f.is_fading());
+        for fader in faders.iter_mut() {
+            if let Err(e) = fader.tick(now) {
+                println!("Error changing gamma of {}: {}", fader.device.name(), e);
+            }
+        }
+
+        let timeout = match fading {
+            true => FADE_STEP.min(wake - now),
+            false => wake - now,
+        };
+
+        match clock.wait(events, timeout) {
+            Ok(happened) => {
+                let happened: Vec<Event> = happened
+                    .into_iter()
+                    .filter(|e| !matches!(e, Event::Device(uevent) if !uevent.wakes()))
+                    .collect();
+                if !happened.is_empty() {
+                    return happened;
+                }
+            }
+            Err(e) => {
+                eprintln!("Error waiting for events: {}", e);
+                clock.sleep(timeout);
+            }
+        }
+    }
+}
+
+/* Returns the config if every brightness it sets fits the devices the faders drive,
+ * or the values that don't as a ConfigError::Invalid.
+ * */
+fn check_devices<T: Backlight>(
+    config: Config,
+    faders: &[Fader<T>],
+) -> Result<Config, config::ConfigError> {
+    let problems: Vec<String> = faders
+        .iter()
+        .flat_map(|f| config.check_device(f.device.name(), f.device.max_brightness()))
+        .collect();
+
+    match problems.is_empty() {
+        true => Ok(config),
+        false => Err(config::ConfigError::Invalid(problems)),
+    }
+}
+
+/* Reads the config files again and swaps the result into the battery info.
+ * Returns the files that were read, see config::load_layered.
+ *
+ * If the new config can't be read, or sets a brightness one of the devices can't go to,
+ * the old one is kept, an error is printed, and returned.
+ * */
+fn reload_config<T: Backlight>(
+    info: &mut BatteryInfo,
+    faders: &[Fader<T>],
+    paths: &[PathBuf],
+    required: Option<&Path>,
+) -> Result<Vec<PathBuf>, error::Error> {
+    let loaded = config::load_layered(paths, required).and_then(|loaded| {
+        let config =
+            check_devices(loaded.config, faders).map_err(|source| error::Error::Config {
+                path: config::describe_files(&loaded.files),
+                source,
+            })?;
+        Ok((config, loaded.files))
+    });
+
+    match loaded {
+        Ok((config, files)) => {
+            *info.gamma_values = config;
+            println!("Reloaded config from {}", config::describe_files(&files));
+            Ok(files)
+        }
+        Err(e) => {
+            eprintln!("{}\n gamma_daemon will keep using the old config", e);
+            Err(e)
+        }
+    }
+}
+
+/* Reloads the config files, and applies them right away unless the daemon is paused.
+ *
+ * Returns the files that were read, or why the new config couldn't be, in which case the old one
+ * is kept.
+ * */
+fn reload<T: Backlight, B: BatterySource>(
+    info: &mut BatteryInfo,
+    batteries: &mut Batteries<B>,
+    faders: &mut [Fader<T>],
+    paths: &[PathBuf],
+    required: Option<&Path>,
+    now: Instant,
+) -> Result<Vec<PathBuf>, error::Error> {
+    let old_selection = info.gamma_values.battery.clone();
+    let files = reload_config(info, faders, paths, required)?;
+
+    if info.gamma_values.battery != old_selection {
+        batteries.select(&info.gamma_values.battery);
+        update(info, batteries);
+    }
+    let transition = info.policy.reload();
+    act(faders, info, transition, now);
+    Ok(files)
+}
+
+/* Answers a request from the control socket.
+ *
+ * reload and quit need the rest of the daemon's state, so run handles those itself.
+ * */
+fn handle_request<T: Backlight>(
+    request: Request,
+    faders: &mut [Fader<T>],
+    info: &mut BatteryInfo,
+    config_files: &[PathBuf],
+    now: Instant,
+) -> Response {
+    match request {
+        Request::Status => Response::status(status(faders, info, config_files)),
+        Request::Set { level } => {
+            let response = set_level(faders, info, level, now);
+            let held = faders.iter().any(|f| f.overridden_since().is_some());
+            let transition = info.policy.overrides(held, false);
+            act(faders, info, transition, now);
+            response
+        }
+        Request::Pause => {
+            let transition = info.policy.pause();
+            act(faders, info, transition, now);
+            println!("Paused");
+            Response::ok()
+        }
+        Request::Resume => {
+            let transition = info.policy.resume();
+            println!("Resumed");
+            act(faders, info, transition, now);
+            Response::ok()
+        }
+        Request::Reload | Request::Quit => Response::ok(),
+    }
 }
 
-/* This function will run the perform_screen_change function, and direct
+/* Fades every device to level, and leaves it there like a change made by hand, see check_overrides.
+ * Every device is tried, and the error reply names each one that couldn't be set.
+ * */
+fn set_level<T: Backlight>(
+    faders: &mut [Fader<T>],
+    info: &BatteryInfo,
+    level: Level,
+    now: Instant,
+) -> Response {
+    let mut errors = Vec::new();
+
+    for fader in faders.iter_mut() {
+        let fade = info.gamma_values.for_device(fader.device.name()).fade;
+        let gamma = level.resolve(fader.device.max_brightness());
+
+        match fader.fade_to(gamma, fade.duration(), fade.easing, now) {
+            Ok(_) => {
+                fader.set_override(now);
+                println!("Set gamma of {} to {}", fader.device.name(), gamma);
+            }
+            Err(e) => errors.push(format!("{}: {}", fader.device.name(), e)),
+        }
+    }
+
+    match errors.is_empty() {
+        true => Response::ok(),
+        false => Response::error(errors.join(", ")),
+    }
+}
+
+// what the status command replies with
+fn status<T: Backlight>(
+    faders: &[Fader<T>],
+    info: &BatteryInfo,
+    config_files: &[PathBuf],
+) -> Status {
+    Status {
+        paused: info.policy.mode == Mode::Paused,
+        battery_state: info.new_status.to_string(),
+        state_of_charge: info.soc,
+        ac_online: info.new_ac_status == '1',
+        config_files: config_files
+            .iter()
+            .map(|f| f.display().to_string())
+            .collect(),
+        devices: faders
+            .iter()
+            .map(|fader| DeviceStatus {
+                name: fader.device.name().to_string(),
+                brightness: fader.target(),
+                max_brightness: fader.device.max_brightness(),
+                overridden: fader.overridden_since().is_some(),
+            })
+            .collect(),
+    }
+}
+
+/* Sets every device back to the brightness it had when the daemon started, or to the config's
+ * on_exit level if there is one. This fades like any other change, and waits for the fades
+ * (including any already running) to finish.
+ *
+ * startup holds the brightness of each device when the daemon started, None if it couldn't be read.
+ * Devices with neither a startup brightness nor an on_exit level are left alone.
+ * */
+fn restore_brightness<T: Backlight>(
+    clock: &impl Clock,
+    faders: &mut [Fader<T>],
+    startup: &[Option<u32>],
+    config: &Config,
+) {
+    let now = clock.now();
+
+    for (fader, startup) in faders.iter_mut().zip(startup) {
+        let device_config = config.for_device(fader.device.name());
+        let target = match (device_config.on_exit, startup) {
+            (Some(level), _) => level.resolve(fader.device.max_brightness()),
+            (None, Some(brightness)) => *brightness,
+            (None, None) => continue,
+        };
+
+        let fade = device_config.fade;
+        if let Err(e) = fader.fade_to(target, fade.duration(), fade.easing, now) {
+            println!("Error restoring gamma of {}: {}", fader.device.name(), e);
+        }
+    }
+
+    for fader in faders.iter_mut() {
+        match fader.finish(clock) {
+            Ok(_) => {
+                if let Some(g) = fader.target() {
+                    println!("Restored gamma of {} to {}", fader.device.name(), g);
+                }
+            }
+            Err(e) => println!("Error restoring gamma of {}: {}", fader.device.name(), e),
+        }
+    }
+}
+
+/* This function will run the perform_screen_change function for every device, and direct
  * success or error messages to std::out or std::err based on the result of
  * perform_screen_change.
+ *
+ * A device failing to change doesn't stop the others from changing. Devices the user has
+ * overridden are skipped.
  * */
-fn try_change<T: Backlight>(device: &T, info: &BatteryInfo) {
-    match perform_screen_change(device, info) {
-        Ok(g) => {
-            println!("Changed gamma to {}", g);
+fn try_change<T: Backlight>(faders: &mut [Fader<T>], info: &BatteryInfo, now: Instant) {
+    for fader in faders.iter_mut() {
+        if fader.overridden_since().is_some() {
+            continue;
         }
-        //If there is an error changing the gamma, print an error
-        Err(e) => {
-            println!("Error changing gamma: {}", e);
+        match perform_screen_change(fader, info, now) {
+            Ok(g) => {
+                println!("Changed gamma of {} to {}", fader.device.name(), g);
+            }
+            //If there is an error changing the gamma, print an error
+            Err(e) => {
+                println!("Error changing gamma of {}: {}", fader.device.name(), e);
+            }
+        };
+    }
+}
+
+/* Moves the policy in info along transition, and does what it decided about the brightness */
+fn act<T: Backlight>(
+    faders: &mut [Fader<T>],
+    info: &mut BatteryInfo,
+    transition: Transition,
+    now: Instant,
+) {
+    info.policy = transition.to;
+
+    match transition.action {
+        Action::Stay => {}
+        Action::Apply => try_change(faders, info, now),
+        Action::Follow => {
+            if curve_moved(faders, info) {
+                try_change(faders, info, now);
+            }
         }
-    };
+        Action::Reclaim => {
+            clear_overrides(faders);
+            try_change(faders, info, now);
+        }
+    }
+}
+
+/* Moves the policy on to the battery state, AC and charge in info, and sets the brightness to
+ * match. Devices changed by hand are looked at first, so the power changing hands them back.
+ * The daemon does this every loop, and trace::simulate for every sample.
+ * */
+fn decide<T: Backlight>(faders: &mut [Fader<T>], info: &mut BatteryInfo, now: Instant) {
+    let expired = check_overrides(faders, &info.gamma_values, now);
+    let held = faders.iter().any(|f| f.overridden_since().is_some());
+    let transition = info.policy.overrides(held, expired);
+    act(faders, info, transition, now);
+
+    // the first loop always sets the brightness, see policy.rs
+    let names: Vec<&str> = faders.iter().map(|f| f.device.name()).collect();
+    let transition = info.policy.next(
+        info.new_status,
+        info.new_ac_status == '1',
+        info.soc,
+        &info.gamma_values,
+        &names,
+    );
+    act(faders, info, transition, now);
+}
+
+/* Returns '1' if any AC supply (or the one pinned in the config) is online, otherwise '0'.
+ * Supplies are looked up every time, so a USB-C charger showing up later is still noticed.
+ *
+ * A machine without batteries must be running on AC, so that's always '1'.
+ * */
+fn ac_status<B: BatterySource, P: PowerSource>(
+    power: &P,
+    config: &Config,
+    batteries: &Batteries<B>,
+) -> char {
+    if batteries.is_empty() {
+        return '1';
+    }
+
+    match power.ac_online(config.power_supply.as_deref()) {
+        true => '1',
+        false => '0',
+    }
+}
+
+/* How the daemon should run, from the command line */
+pub struct RunOptions {
+    // the config files to layer, most important first, see config::search_paths
+    pub config_paths: Vec<PathBuf>,
+    // the file given on the command line, if any, which has to exist
+    pub config: Option<PathBuf>,
+    // stay in the foreground instead of forking and logging to /tmp. This is what systemd and
+    // containers want, together with log_to_stderr.
+    pub foreground: bool,
+    // where to listen for commands, see control::socket_path. None for no control socket.
+    pub control_socket: Option<PathBuf>,
+    // the trace to record every loop's battery state and AC to, see trace
+    pub record: Option<PathBuf>,
 }
 
 /* Run the Daemon.
- * Returns a result with a () success type, and a battery::Error if there is any issue reading from
- * the notebook battery
+ * Every device in devices is driven, each with the brightness table its [[device]] section selects,
+ * and the batteries and AC supplies are read from batteries and power. Every sleep and timestamp
+ * goes through clock.
+ * The daemon runs until it is sent SIGTERM or SIGINT, at which point the brightness is restored.
+ *
+ * If systemd started us with $NOTIFY_SOCKET set, it is told when we are ready and stopping, and
+ * pinged as often as its watchdog wants.
+ *
+ * Returns an error if the daemon can't start, or hits one it can't recover from, i.e: the
+ * batteries can't be read for BATTERY_RETRIES loops in a row. The brightness is restored first
+ * if the daemon was already running.
  *
  */
-pub fn run(device: &MonitorDevice, path: &String) -> Result<(), battery::Error> {
-    let delay: u64 = 1; // check for changes every second
-    let sleep_duration = Duration::from_secs(delay);
+pub fn run<T: Backlight + Clone, B: BatterySource, P: PowerSource, C: Clock>(
+    devices: &[T],
+    batteries: B,
+    power: P,
+    clock: C,
+    options: &RunOptions,
+) -> Result<(), error::Error> {
+    let delay: u64 = 1; // without uevents, check for changes every second
+    let config_paths = &options.config_paths;
+
+    let mut faders: Vec<Fader<T>> = devices.iter().cloned().map(Fader::new).collect();
+    // a broken config stops us here, rather than silently running with the default
+    let loaded = config::load_layered(config_paths, options.config.as_deref())?;
+    let mut config_files = loaded.files;
+    let config: Config =
+        check_devices(loaded.config, &faders).map_err(|source| error::Error::Config {
+            path: config::describe_files(&config_files),
+            source,
+        })?;
+    match config_files.is_empty() {
+        true => eprintln!("No config file found, gamma_daemon will use the default config"),
+        false => println!("Read config from {}", config::describe_files(&config_files)),
+    }
+    // what each device was at before we touched it, so we can put it back when we stop
+    let startup: Vec<Option<u32>> = faders.iter().map(|f| f.device.brightness().ok()).collect();
 
-    let env: String = match env::var("USER") {
-        Ok(s) => s,
-        Err(_) => "NAN".to_string(),
-    };
+    let mut batteries = Batteries::new(batteries, &config.battery);
+    let mut battery_info = Box::new(new_battery_info(config, &batteries));
 
-    let config_file = if path == "NAN" {
-        "/home/".to_owned() + &env + "/.config/GammaDaemon/conf.toml"
+    let pinned = battery_info.gamma_values.power_supply.as_deref();
+    let supplies = power.supplies(pinned);
+    if batteries.is_empty() {
+        eprintln!("No battery found, gamma_daemon will treat this machine as always on AC and use the ac_in brightness until one shows up");
     } else {
-        path.to_string()
-    };
-    let config: Config = config::load_config(config_file);
+        match supplies {
+            Ok(supplies) if !supplies.is_empty() => {}
+            Ok(_) => {
+                eprintln!("No AC supply found, gamma_daemon will assume the notebook is unplugged")
+            }
+            Err(e) => eprintln!(
+                "{}, gamma_daemon will assume the notebook is unplugged",
+                error::Error::PowerSupply(e)
+            ),
+        }
+    }
 
-    let manager = battery::Manager::new()?;
-    let mut battery = manager.batteries()?.next().unwrap()?;
-    let mut battery_info = Box::new(new_battery_info(config, &mut battery));
+    // opened before we fork, so a relative path is still where the user meant
+    let mut recorder = match &options.record {
+        Some(path) => Some(
+            Recorder::create(path).map_err(|source| error::Error::Trace {
+                context: format!("Can't record to {}", path.display()),
+                source,
+            })?,
+        ),
+        None => None,
+    };
 
-    let old_status = battery.state();
-    let old_ac_status: String = read_file::get_contents(AC_STATUS_FILE).unwrap();
+    if !options.foreground {
+        daemonize()?;
+    }
+    let mut notifier = Notifier::from_env();
 
-    daemonize();
+    // listen for uevents, config changes and SIGHUP, so we only wake up when something changes
+    let events = Events::open(config_paths, options.control_socket.as_deref());
 
-    battery_info.old_status = old_status;
-    battery_info.old_ac_status = old_ac_status.chars().next().unwrap_or('0');
+    if let Some(notifier) = &notifier {
+        notifier.notify("READY=1");
+    }
 
-    update(&mut battery_info, &battery);
-    let mut low_set = true; // to keep track when we set the screen gamma to low.
-                            // We only want to set the low gamma once until we are
-                            // no longer at low battery.
+    let mut battery_failures = 0; // loops in a row we couldn't read the batteries
     loop {
-        let new_ac_status: String = read_file::get_contents(AC_STATUS_FILE).unwrap();
+        let new_ac_status = ac_status(&power, &battery_info.gamma_values, &batteries);
 
-        let status = battery.state();
+        let status = batteries.state();
 
         // Put the new data into the battery info
         battery_info.new_status = status;
-        battery_info.new_ac_status = new_ac_status.chars().next().unwrap_or('0');
+        battery_info.new_ac_status = new_ac_status;
+        if let Some(r) = &mut recorder {
+            if let Err(e) = r.record(&battery_info, clock.system_time()) {
+                eprintln!(
+                    "Can't record to the trace, gamma_daemon will stop recording: {}",
+                    e
+                );
+                recorder = None;
+            }
+        }
+
+        let now = clock.now();
+        decide(&mut faders, &mut battery_info, now);
+
+        // The battery's charge doesn't always send a uevent, so we still poll for that every
+        // poll_interval seconds.
+        let mut sleep_duration = match events.has_uevents() {
+            true => Duration::from_secs(battery_info.gamma_values.poll_interval),
+            false => Duration::from_secs(delay),
+        };
+        if let Some(expiry) = next_override_expiry(&faders, &battery_info.gamma_values, now) {
+            sleep_duration = sleep_duration.min(expiry);
+        }
+        if let Some(ping) = notifier.as_mut().and_then(|n| n.keep_alive(now)) {
+            sleep_duration = sleep_duration.min(ping);
+        }
+        if battery_failures > 0 {
+            sleep_duration = sleep_duration.min(RETRY_DELAY);
+        }
+        let woken_by = match loop_update(
+            &clock,
+            &mut battery_info,
+            &mut batteries,
+            &mut faders,
+            &events,
+            sleep_duration,
+            &mut battery_failures,
+        ) {
+            Ok(woken_by) => woken_by,
+            Err(e) => {
+                restore_brightness(&clock, &mut faders, &startup, &battery_info.gamma_values);
+                return Err(e);
+            }
+        };
+
+        let now = clock.now();
+        let mut quit = false;
+        for event in woken_by {
+            match event {
+                Event::Device(uevent) => {
+                    if uevent.subsystem == "backlight" && uevent.action == "add" {
+                        println!(
+                            "New backlight device at {}, restart gamma_daemon to drive it",
+                            uevent.devpath
+                        );
+                    }
+                }
+                Event::Reload => {
+                    // reload prints what went wrong, and the old config is still good to use
+                    if let Ok(files) = reload(
+                        &mut battery_info,
+                        &mut batteries,
+                        &mut faders,
+                        config_paths,
+                        options.config.as_deref(),
+                        now,
+                    ) {
+                        config_files = files;
+                    }
+                }
+                Event::Quit => quit = true,
+                Event::Command(Request::Reload, client) => {
+                    let reloaded = reload(
+                        &mut battery_info,
+                        &mut batteries,
+                        &mut faders,
+                        config_paths,
+                        options.config.as_deref(),
+                        now,
+                    );
+                    client.reply(&match reloaded {
+                        Ok(files) => {
+                            config_files = files;
+                            Response::ok()
+                        }
+                        Err(e) => Response::error(e.to_string()),
+                    });
+                }
+                Event::Command(Request::Quit, client) => {
+                    client.reply(&Response::ok());
+                    quit = true;
+                }
+                Event::Command(request, client) => {
+                    let response =
+                        handle_request(request, &mut faders, &mut battery_info, &config_files, now);
+                    client.reply(&response);
+                }
+            }
+        }
 
-        if status_changed(&battery_info, &low_set) {
-            try_change(device, &battery_info);
-            low_set = !low_set; // since the status changed we possibly set the brightness to low,
-                                // we dont want to keep doing it.
+        if quit {
+            if let Some(notifier) = &notifier {
+                notifier.notify("STOPPING=1");
+            }
+            restore_brightness(&clock, &mut faders, &startup, &battery_info.gamma_values);
+            println!("gamma_daemon stopped");
+            return Ok(());
         }
-        loop_update(&manager, &mut battery_info, &mut battery, sleep_duration)?;
     }
 }
 
-/* Returns a Result with a success value of (), and a battery::Error if there was an error changing
+/* Returns a Result with a success value of the new gamma, and a BacklightError if there was an error changing
  *  the screen Gamma
+ *
+ *  The gamma is calculated from the brightness table the config selects for this device, and
+ *  faded to as the config's [fade] section says. Any fade already running on the device is replaced.
  */
-fn perform_screen_change<T: Backlight>(device: &T, info: &BatteryInfo) -> Result<u32, Error> {
-    let gamma: u32 = calc_new_brightness(info);
-
-    match device.change_gamma(gamma) {
+fn perform_screen_change<T: Backlight>(
+    fader: &mut Fader<T>,
+    info: &BatteryInfo,
+    now: Instant,
+) -> Result<u32, BacklightError> {
+    let device = &fader.device;
+    let config = info.gamma_values.for_device(device.name());
+    let gamma: u32 = calc_new_brightness(info, &config, device.max_brightness());
+
+    match fader.fade_to(gamma, config.fade.duration(), config.fade.easing, now) {
         Ok(_) => Ok(gamma),
         Err(e) => Err(e),
     }
@@ -251,15 +965,46 @@ fn perform_screen_change<T: Backlight>(device: &T, info: &BatteryInfo) -> Result
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::daemon::config::Config;
+    use crate::daemon::clock::manual::ManualClock;
+    use crate::daemon::clock::SystemClock;
+    use crate::daemon::config::{Config, DeviceConfig, Level};
+    use crate::daemon::control::Request;
+    use crate::daemon::policy::Phase;
+    use crate::daemon::sysfs::fixture::Fixture;
+    use crate::daemon::sysfs::SysfsBatteries;
     use battery::State;
+    use std::cell::Cell;
+    use std::sync::{Arc, Mutex};
 
-    struct MockMonitorDevice;
+    // the backlights the policy decides for in the tests that call it directly
+    const DEVICES: [&str; 1] = ["intel_backlight"];
+
+    #[derive(Default)]
+    struct MockMonitorDevice {
+        // brightness someone else set, i.e: with the brightness keys
+        by_hand: Cell<Option<u32>>,
+    }
 
     impl Backlight for MockMonitorDevice {
-        fn change_gamma(&self, gamma: u32) -> Result<(), Error> {
+        fn name(&self) -> &str {
+            "mock_backlight"
+        }
+
+        fn max_brightness(&self) -> u32 {
+            255
+        }
+
+        fn brightness(&self) -> Result<u32, BacklightError> {
+            Ok(0)
+        }
+
+        fn actual_brightness(&self) -> Result<u32, BacklightError> {
+            Ok(self.by_hand.get().unwrap_or(0))
+        }
+
+        fn change_gamma(&self, gamma: u32) -> Result<(), BacklightError> {
             if gamma > 255 {
-                return Err(Error::InvalidBrightnessLevel {
+                return Err(BacklightError::OutOfRange {
                     given: gamma,
                     max: 255,
                 });
@@ -270,230 +1015,510 @@ mod tests {
     }
 
     impl MockMonitorDevice {
-        fn new() -> Self {
-            MockMonitorDevice
+        fn new() -> Fader<Self> {
+            Fader::new(MockMonitorDevice::default())
         }
     }
 
     #[test]
     fn test_successful_brightness_change() {
-        let device = MockMonitorDevice::new();
+        let mut device = MockMonitorDevice::new();
 
         let battery_info1 = BatteryInfo {
             soc: 0.75,
-            old_status: State::Charging,
+            policy: Policy::default(),
             new_status: State::Discharging,
-            old_ac_status: 'C',
             new_ac_status: 'D',
             gamma_values: Box::new(Config {
-                full: 225,
-                low: 100,
+                full: Level::Raw(225),
+                low: Level::Raw(100),
                 low_perc: 25,
-                charging: 255,
-                discharging: 155,
-                unknown: 155,
-                ac_in: 225,
+                charging: Level::Raw(255),
+                discharging: Level::Raw(155),
+                unknown: Level::Raw(155),
+                ac_in: Level::Raw(225),
+                ..Default::default()
             }),
         };
 
-        let result = perform_screen_change(&device, &battery_info1);
+        let result = perform_screen_change(&mut device, &battery_info1, Instant::now());
 
         assert!(result.is_ok());
     }
 
     #[test]
     fn test_brightness_change_failure() {
-        let device = MockMonitorDevice::new();
+        let mut device = MockMonitorDevice::new();
 
         let battery_info1 = BatteryInfo {
             soc: 0.75,
-            old_status: State::Charging,
+            policy: Policy::default(),
             new_status: State::Discharging,
-            old_ac_status: 'C',
             new_ac_status: 'D',
             gamma_values: Box::new(Config {
-                full: 225,
-                low: 100,
+                full: Level::Raw(225),
+                low: Level::Raw(100),
                 low_perc: 25,
-                charging: 255,
-                discharging: 1155,
-                unknown: 155,
-                ac_in: 225,
+                charging: Level::Raw(255),
+                discharging: Level::Raw(1155),
+                unknown: Level::Raw(155),
+                ac_in: Level::Raw(225),
+                ..Default::default()
             }),
         };
 
-        let result = perform_screen_change(&device, &battery_info1);
+        let result = perform_screen_change(&mut device, &battery_info1, Instant::now());
 
-        assert!(!result.is_ok());
+        assert!(result.is_err());
     }
 
     #[test]
-    fn test_change() {
+    fn test_device_section_brightness() {
+        let mut device = MockMonitorDevice::new();
+
         let mut battery_info1 = BatteryInfo {
-            soc: 75.0,
-            old_status: State::Charging,
+            soc: 0.75,
+            policy: Policy::default(),
             new_status: State::Discharging,
-            old_ac_status: 'C',
             new_ac_status: 'D',
             gamma_values: Box::new(Config {
-                full: 225,
-                low: 100,
+                full: Level::Raw(225),
+                low: Level::Raw(100),
                 low_perc: 25,
-                charging: 255,
-                discharging: 155,
-                unknown: 155,
-                ac_in: 225,
+                charging: Level::Raw(255),
+                discharging: Level::Raw(155),
+                unknown: Level::Raw(155),
+                ac_in: Level::Raw(225),
+                device: vec![DeviceConfig {
+                    name: "mock_*".to_string(),
+                    discharging: Some(Level::Raw(80)),
+                    ..Default::default()
+                }],
+                ..Default::default()
             }),
         };
 
-        let low = false;
-        assert_eq!(true, status_changed(&battery_info1, &low));
-        battery_info1.old_status = State::Discharging;
-        battery_info1.old_ac_status = 'D';
-        assert_eq!(false, status_changed(&battery_info1, &low));
+        assert_eq!(
+            perform_screen_change(&mut device, &battery_info1, Instant::now()).unwrap(),
+            80
+        );
+
+        battery_info1.gamma_values.device[0].name = "intel_backlight".to_string();
+        assert_eq!(
+            perform_screen_change(&mut device, &battery_info1, Instant::now()).unwrap(),
+            155
+        );
     }
 
     #[test]
-    fn test_new_gamma_unknown() {
-        let gamma_values: Config = Config {
-            full: 200,
-            low: 100,
-            low_perc: 20,
-            charging: 200,
-            discharging: 155,
-            unknown: 155,
-            ac_in: 200,
+    fn test_percentage_brightness() {
+        let mut device = MockMonitorDevice::new();
+
+        let battery_info1 = BatteryInfo {
+            soc: 0.75,
+            policy: Policy::default(),
+            new_status: State::Discharging,
+            new_ac_status: 'D',
+            gamma_values: Box::new(Config {
+                full: Level::Percent(100.0),
+                low: Level::Percent(20.0),
+                low_perc: 25,
+                charging: Level::Percent(100.0),
+                discharging: Level::Percent(60.0),
+                unknown: Level::Percent(60.0),
+                ac_in: Level::Percent(100.0),
+                ..Default::default()
+            }),
         };
 
-        let test_info: BatteryInfo = BatteryInfo {
+        assert_eq!(
+            perform_screen_change(&mut device, &battery_info1, Instant::now()).unwrap(),
+            153
+        );
+
+        let config = &battery_info1.gamma_values;
+        assert_eq!(calc_new_brightness(&battery_info1, config, 96000), 57600);
+        assert_eq!(calc_new_brightness(&battery_info1, config, 1), 1);
+    }
+
+    #[test]
+    fn test_curve_brightness() {
+        let mut device = MockMonitorDevice::new();
+        let curve = toml::from_str::<Config>(
+            "full = 255\nlow = 100\nlow_perc = 25\ncharging = 255\ndischarging = 155\nunknown = 155\nac_in = 255\n[curve]\n100 = 200\n0 = 0\n",
+        )
+        .unwrap()
+        .curve;
+
+        let mut battery_info1 = BatteryInfo {
             soc: 0.5,
-            old_status: State::Unknown,
-            new_status: State::Unknown,
-            old_ac_status: '0',
+            policy: Policy::default(),
+            new_status: State::Discharging,
             new_ac_status: '0',
-            gamma_values: Box::new(gamma_values),
+            gamma_values: Box::new(Config {
+                curve,
+                ..Default::default()
+            }),
         };
 
-        let gamma = calc_new_brightness(&test_info);
+        let config = &battery_info1.gamma_values;
+        assert_eq!(calc_new_brightness(&battery_info1, config, 255), 100);
+
+        // no brightness set yet, so the curve has moved
+        assert!(curve_moved(std::slice::from_ref(&device), &battery_info1));
+        perform_screen_change(&mut device, &battery_info1, Instant::now()).unwrap();
+        assert!(!curve_moved(std::slice::from_ref(&device), &battery_info1));
+
+        // the battery drained, so the curve wants a dimmer screen
+        battery_info1.soc = 0.4;
+        assert!(curve_moved(std::slice::from_ref(&device), &battery_info1));
+        assert_eq!(
+            perform_screen_change(&mut device, &battery_info1, Instant::now()).unwrap(),
+            80
+        );
+
+        // the curve is ignored unless we are discharging
+        battery_info1.new_status = State::Charging;
+        battery_info1.soc = 0.2;
+        assert!(!curve_moved(std::slice::from_ref(&device), &battery_info1));
+        let config = &battery_info1.gamma_values;
+        assert_eq!(calc_new_brightness(&battery_info1, config, 255), 255);
+    }
 
-        assert_eq!(gamma, 155);
-        let gamma_values: Config = Config {
-            full: 200,
-            low: 100,
-            low_perc: 20,
-            charging: 200,
-            discharging: 155,
-            unknown: 155,
-            ac_in: 200,
+    #[test]
+    fn test_reload_config() {
+        let faders = vec![MockMonitorDevice::new()];
+        let mut battery_info1 = BatteryInfo {
+            soc: 0.75,
+            policy: Policy::default(),
+            new_status: State::Discharging,
+            new_ac_status: '0',
+            gamma_values: Box::new(Config::default()),
         };
 
-        let test_info: BatteryInfo = BatteryInfo {
-            soc: 50.0,
-            old_status: State::Full,
-            new_status: State::Unknown,
-            old_ac_status: '1',
+        let temp_file_path =
+            std::env::temp_dir().join(format!("gamma_daemon_reload_{}.toml", std::process::id()));
+        let path = [temp_file_path.clone()];
+
+        // a broken config keeps the old one
+        std::fs::write(&temp_file_path, "full = 200\nlow = \"dim\"").unwrap();
+        assert!(reload_config(&mut battery_info1, &faders, &path, None).is_err());
+        assert_eq!(*battery_info1.gamma_values, Config::default());
+
+        // so does one the device can't reach
+        std::fs::write(&temp_file_path, "full = 900\n").unwrap();
+        assert!(reload_config(&mut battery_info1, &faders, &path, None).is_err());
+        assert_eq!(*battery_info1.gamma_values, Config::default());
+
+        std::fs::write(&temp_file_path, "discharging = 90\n").unwrap();
+        assert_eq!(
+            reload_config(&mut battery_info1, &faders, &path, None).unwrap(),
+            path.to_vec()
+        );
+        assert_eq!(battery_info1.gamma_values.discharging, Level::Raw(90));
+
+        std::fs::remove_file(temp_file_path).unwrap();
+    }
+
+    #[test]
+    fn test_restore_brightness() {
+        let mut faders = vec![MockMonitorDevice::new(), MockMonitorDevice::new()];
+        let mut config = Config::default();
+        let clock = ManualClock::new();
+
+        // back to what they were at when we started
+        restore_brightness(&clock, &mut faders, &[Some(120), None], &config);
+        assert_eq!(faders[0].target(), Some(120));
+        assert_eq!(faders[1].target(), None);
+
+        // an on_exit level wins over the startup brightness
+        config.on_exit = Some(Level::Percent(20.0));
+        restore_brightness(&clock, &mut faders, &[Some(120), None], &config);
+        assert_eq!(faders[0].target(), Some(51));
+        assert_eq!(faders[1].target(), Some(51));
+        assert!(faders.iter().all(|f| !f.is_fading()));
+    }
+
+    #[test]
+    fn test_manual_override() {
+        let mut faders = vec![MockMonitorDevice::new(), MockMonitorDevice::new()];
+        let mut battery_info1 = BatteryInfo {
+            soc: 0.75,
+            policy: Policy::default(),
+            new_status: State::Discharging,
             new_ac_status: '0',
-            gamma_values: Box::new(gamma_values),
+            gamma_values: Box::new(Config {
+                override_timeout: Some(600),
+                ..Default::default()
+            }),
         };
+        let start = Instant::now();
+
+        try_change(&mut faders, &battery_info1, start);
+        assert!(!check_overrides(
+            &mut faders,
+            &battery_info1.gamma_values,
+            start
+        ));
+        assert!(faders.iter().all(|f| f.overridden_since().is_none()));
+
+        // the user turned the first screen up by hand
+        faders[0].device.by_hand.set(Some(240));
+        assert!(!check_overrides(
+            &mut faders,
+            &battery_info1.gamma_values,
+            start
+        ));
+        assert_eq!(faders[0].overridden_since(), Some(start));
+        assert_eq!(faders[1].overridden_since(), None);
+
+        // so a new config only changes the other screen
+        battery_info1.gamma_values.discharging = Level::Raw(90);
+        try_change(&mut faders, &battery_info1, start);
+        assert_eq!(faders[0].target(), Some(155));
+        assert_eq!(faders[1].target(), Some(90));
+
+        let config = &battery_info1.gamma_values;
+        assert_eq!(
+            next_override_expiry(&faders, config, start + Duration::from_secs(60)),
+            Some(Duration::from_secs(540))
+        );
+
+        // until the override times out
+        let later = start + Duration::from_secs(600);
+        assert!(check_overrides(&mut faders, config, later));
+        assert_eq!(faders[0].overridden_since(), None);
+        assert_eq!(next_override_expiry(&faders, config, later), None);
+        try_change(&mut faders, &battery_info1, later);
+        assert_eq!(faders[0].target(), Some(90));
+
+        // plugging in the charger hands the brightness back too
+        faders[0].device.by_hand.set(Some(10));
+        check_overrides(&mut faders, &battery_info1.gamma_values, later);
+        assert!(faders[0].overridden_since().is_some());
+        let config = &battery_info1.gamma_values;
+        battery_info1.policy = Policy::default()
+            .next(State::Discharging, false, 0.75, config, &DEVICES)
+            .to
+            .overrides(true, false)
+            .to;
+        battery_info1.new_ac_status = '1';
+        let transition = battery_info1.policy.next(
+            State::Discharging,
+            true,
+            0.75,
+            &battery_info1.gamma_values,
+            &DEVICES,
+        );
+        assert_eq!(transition.action, Action::Reclaim);
+        act(&mut faders, &mut battery_info1, transition, later);
+        assert!(faders.iter().all(|f| f.overridden_since().is_none()));
+        assert_eq!(battery_info1.policy.mode, Mode::Auto);
+    }
 
-        let gamma = calc_new_brightness(&test_info);
+    #[test]
+    fn test_control_requests() {
+        let mut faders = vec![MockMonitorDevice::new()];
+        let files = [PathBuf::from("conf.toml")];
+        let mut info = BatteryInfo {
+            soc: 0.75,
+            policy: Policy::default(),
+            new_status: State::Discharging,
+            new_ac_status: '0',
+            gamma_values: Box::new(Config::default()),
+        };
+        let set = Request::Set {
+            level: Level::Percent(20.0),
+        };
+        let response = handle_request(set, &mut faders, &mut info, &files, Instant::now());
+        assert_eq!(response, Response::ok());
+        assert_eq!(faders[0].target(), Some(51));
+        // a level set over the socket sticks like one set by hand
+        assert!(faders[0].overridden_since().is_some());
+        assert_eq!(info.policy.mode, Mode::Override);
+
+        let set = Request::Set {
+            level: Level::Raw(300),
+        };
+        let response = handle_request(set, &mut faders, &mut info, &files, Instant::now());
+        assert!(!response.ok);
+
+        let response = handle_request(
+            Request::Pause,
+            &mut faders,
+            &mut info,
+            &files,
+            Instant::now(),
+        );
+        assert_eq!(response, Response::ok());
+        assert_eq!(info.policy.mode, Mode::Paused);
+
+        let response = handle_request(
+            Request::Status,
+            &mut faders,
+            &mut info,
+            &files,
+            Instant::now(),
+        );
+        let status = response.status.unwrap();
+        assert!(status.paused);
+        assert_eq!(status.battery_state, "discharging");
+        assert!(!status.ac_online);
+        assert_eq!(status.config_files, vec!["conf.toml"]);
+        assert_eq!(
+            status.devices,
+            vec![DeviceStatus {
+                name: "mock_backlight".to_string(),
+                brightness: Some(51),
+                max_brightness: 255,
+                overridden: true,
+            }]
+        );
+
+        // resuming hands the brightness back and sets it right away
+        handle_request(
+            Request::Resume,
+            &mut faders,
+            &mut info,
+            &files,
+            Instant::now(),
+        );
+        assert_eq!(info.policy.mode, Mode::Auto);
+        assert!(faders[0].overridden_since().is_none());
+        assert_eq!(faders[0].target(), Some(155));
+    }
 
-        assert_eq!(gamma, 155);
+    #[test]
+    fn test_change() {
+        let battery_info1 = BatteryInfo {
+            soc: 75.0,
+            policy: Policy::default(),
+            new_status: State::Discharging,
+            new_ac_status: 'D',
+            gamma_values: Box::new(Config {
+                full: Level::Raw(225),
+                low: Level::Raw(100),
+                low_perc: 25,
+                charging: Level::Raw(255),
+                discharging: Level::Raw(155),
+                unknown: Level::Raw(155),
+                ac_in: Level::Raw(225),
+                ..Default::default()
+            }),
+        };
+
+        let config = &battery_info1.gamma_values;
+        let ac = battery_info1.new_ac_status == '1';
+        let first = Policy::default().next(
+            battery_info1.new_status,
+            ac,
+            battery_info1.soc,
+            config,
+            &DEVICES,
+        );
+        assert_eq!(first.action, Action::Apply);
+        let again = first.to.next(
+            battery_info1.new_status,
+            ac,
+            battery_info1.soc,
+            config,
+            &DEVICES,
+        );
+        assert_ne!(again.action, Action::Apply);
+
+        // the low brightness is set once on the way down, whatever happened before
+        let charging = again.to.next(State::Charging, true, 0.3, config, &DEVICES);
+        let unplugged = charging
+            .to
+            .next(State::Discharging, false, 0.3, config, &DEVICES);
+        assert_eq!(unplugged.action, Action::Apply);
+        let low = unplugged
+            .to
+            .next(State::Discharging, false, 0.2, config, &DEVICES);
+        assert_eq!(low.action, Action::Apply);
+        assert_eq!(low.to.phase, Phase::Low);
     }
 
     #[test]
-    fn test_new_gamma_charging() {
+    fn test_new_gamma_unknown() {
         let gamma_values: Config = Config {
-            full: 200,
-            low: 100,
+            full: Level::Raw(200),
+            low: Level::Raw(100),
             low_perc: 20,
-            charging: 255,
-            discharging: 155,
-            unknown: 155,
-            ac_in: 200,
+            charging: Level::Raw(200),
+            discharging: Level::Raw(155),
+            unknown: Level::Raw(155),
+            ac_in: Level::Raw(200),
+            ..Default::default()
         };
 
         let test_info: BatteryInfo = BatteryInfo {
             soc: 0.5,
-            old_status: State::Unknown,
-            new_status: State::Charging,
-            old_ac_status: '0',
-            new_ac_status: '1',
+            policy: Policy::default(),
+            new_status: State::Unknown,
+            new_ac_status: '0',
             gamma_values: Box::new(gamma_values),
         };
 
-        let gamma = calc_new_brightness(&test_info);
+        let gamma = calc_new_brightness(&test_info, &test_info.gamma_values, 255);
 
-        assert_eq!(gamma, 255);
+        assert_eq!(gamma, 155);
+    }
 
+    #[test]
+    fn test_new_gamma_charging() {
         let gamma_values: Config = Config {
-            full: 200,
-            low: 100,
+            full: Level::Raw(200),
+            low: Level::Raw(100),
             low_perc: 20,
-            charging: 255,
-            discharging: 155,
-            unknown: 155,
-            ac_in: 200,
+            charging: Level::Raw(255),
+            discharging: Level::Raw(155),
+            unknown: Level::Raw(155),
+            ac_in: Level::Raw(200),
+            ..Default::default()
         };
 
         let test_info: BatteryInfo = BatteryInfo {
             soc: 0.5,
-            old_status: State::Discharging,
+            policy: Policy::default(),
             new_status: State::Charging,
-            old_ac_status: '1',
             new_ac_status: '1',
             gamma_values: Box::new(gamma_values),
         };
 
-        let gamma = calc_new_brightness(&test_info);
+        let gamma = calc_new_brightness(&test_info, &test_info.gamma_values, 255);
 
         assert_eq!(gamma, 255);
     }
+
     #[test]
     fn test_new_gamma_full() {
         let gamma_values: Config = Config {
-            full: 220,
-            low: 100,
+            full: Level::Raw(220),
+            low: Level::Raw(100),
             low_perc: 20,
-            charging: 200,
-            discharging: 155,
-            unknown: 155,
-            ac_in: 200,
+            charging: Level::Raw(200),
+            discharging: Level::Raw(155),
+            unknown: Level::Raw(155),
+            ac_in: Level::Raw(200),
+            ..Default::default()
         };
 
-        let test_info: BatteryInfo = BatteryInfo {
+        let mut test_info: BatteryInfo = BatteryInfo {
             soc: 1.0,
-            old_status: State::Unknown,
+            policy: Policy::default(),
             new_status: State::Full,
-            old_ac_status: '0',
             new_ac_status: '0',
             gamma_values: Box::new(gamma_values),
         };
 
-        let gamma = calc_new_brightness(&test_info);
+        let gamma = calc_new_brightness(&test_info, &test_info.gamma_values, 255);
 
         assert_eq!(gamma, 220);
 
-        let gamma_values: Config = Config {
-            full: 220,
-            low: 100,
-            low_perc: 20,
-            charging: 200,
-            discharging: 155,
-            unknown: 155,
-            ac_in: 200,
-        };
-
-        let test_info: BatteryInfo = BatteryInfo {
-            soc: 1.0,
-            old_status: State::Discharging,
-            new_status: State::Full,
-            old_ac_status: '1',
-            new_ac_status: '1',
-            gamma_values: Box::new(gamma_values),
-        };
-
-        let gamma = calc_new_brightness(&test_info);
+        // full is full, plugged in or not
+        test_info.new_ac_status = '1';
+        let gamma = calc_new_brightness(&test_info, &test_info.gamma_values, 255);
 
         assert_eq!(gamma, 220);
     }
@@ -501,25 +1526,25 @@ mod tests {
     #[test]
     fn test_new_gamma_low() {
         let gamma_values: Config = Config {
-            full: 200,
-            low: 100,
+            full: Level::Raw(200),
+            low: Level::Raw(100),
             low_perc: 20,
-            charging: 200,
-            discharging: 155,
-            unknown: 155,
-            ac_in: 200,
+            charging: Level::Raw(200),
+            discharging: Level::Raw(155),
+            unknown: Level::Raw(155),
+            ac_in: Level::Raw(200),
+            ..Default::default()
         };
 
         let test_info: BatteryInfo = BatteryInfo {
             soc: 0.2,
-            old_status: State::Unknown,
+            policy: Policy::default(),
             new_status: State::Discharging,
-            old_ac_status: '0',
             new_ac_status: '0',
             gamma_values: Box::new(gamma_values),
         };
 
-        let gamma = calc_new_brightness(&test_info);
+        let gamma = calc_new_brightness(&test_info, &test_info.gamma_values, 255);
 
         assert_eq!(gamma, 100);
     }
@@ -527,25 +1552,25 @@ mod tests {
     #[test]
     fn test_new_gamma_discharging() {
         let gamma_values: Config = Config {
-            full: 200,
-            low: 100,
+            full: Level::Raw(200),
+            low: Level::Raw(100),
             low_perc: 20,
-            charging: 200,
-            discharging: 155,
-            unknown: 155,
-            ac_in: 200,
+            charging: Level::Raw(200),
+            discharging: Level::Raw(155),
+            unknown: Level::Raw(155),
+            ac_in: Level::Raw(200),
+            ..Default::default()
         };
 
         let test_info: BatteryInfo = BatteryInfo {
             soc: 50.0,
-            old_status: State::Unknown,
+            policy: Policy::default(),
             new_status: State::Unknown,
-            old_ac_status: '0',
             new_ac_status: '0',
             gamma_values: Box::new(gamma_values),
         };
 
-        let gamma = calc_new_brightness(&test_info);
+        let gamma = calc_new_brightness(&test_info, &test_info.gamma_values, 255);
 
         assert_eq!(gamma, 155);
     }
@@ -553,25 +1578,25 @@ mod tests {
     #[test]
     fn test_new_gamma_unknown_no_ac() {
         let gamma_values: Config = Config {
-            full: 200,
-            low: 100,
+            full: Level::Raw(200),
+            low: Level::Raw(100),
             low_perc: 20,
-            charging: 200,
-            discharging: 155,
-            unknown: 155,
-            ac_in: 200,
+            charging: Level::Raw(200),
+            discharging: Level::Raw(155),
+            unknown: Level::Raw(155),
+            ac_in: Level::Raw(200),
+            ..Default::default()
         };
 
         let test_info: BatteryInfo = BatteryInfo {
             soc: 50.0,
-            old_status: State::Unknown,
+            policy: Policy::default(),
             new_status: State::Unknown,
-            old_ac_status: '0',
             new_ac_status: '0',
             gamma_values: Box::new(gamma_values),
         };
 
-        let gamma = calc_new_brightness(&test_info);
+        let gamma = calc_new_brightness(&test_info, &test_info.gamma_values, 255);
 
         assert_eq!(gamma, 155);
     }
@@ -579,25 +1604,25 @@ mod tests {
     #[test]
     fn test_new_gamma_unknown_ac() {
         let gamma_values: Config = Config {
-            full: 200,
-            low: 100,
+            full: Level::Raw(200),
+            low: Level::Raw(100),
             low_perc: 20,
-            charging: 200,
-            discharging: 155,
-            unknown: 155,
-            ac_in: 200,
+            charging: Level::Raw(200),
+            discharging: Level::Raw(155),
+            unknown: Level::Raw(155),
+            ac_in: Level::Raw(200),
+            ..Default::default()
         };
 
         let test_info: BatteryInfo = BatteryInfo {
             soc: 50.0,
-            old_status: State::Unknown,
+            policy: Policy::default(),
             new_status: State::Unknown,
-            old_ac_status: '0',
             new_ac_status: '1',
             gamma_values: Box::new(gamma_values),
         };
 
-        let gamma = calc_new_brightness(&test_info);
+        let gamma = calc_new_brightness(&test_info, &test_info.gamma_values, 255);
 
         assert_eq!(gamma, 200);
     }
@@ -605,51 +1630,342 @@ mod tests {
     #[test]
     fn test_new_gamma_discharging_low_soc() {
         let gamma_values: Config = Config {
-            full: 200,
-            low: 100,
+            full: Level::Raw(200),
+            low: Level::Raw(100),
             low_perc: 24,
-            charging: 200,
-            discharging: 155,
-            unknown: 155,
-            ac_in: 200,
+            charging: Level::Raw(200),
+            discharging: Level::Raw(155),
+            unknown: Level::Raw(155),
+            ac_in: Level::Raw(200),
+            ..Default::default()
         };
 
         let test_info: BatteryInfo = BatteryInfo {
             soc: 0.2,
-            old_status: State::Unknown,
+            policy: Policy::default(),
             new_status: State::Discharging,
-            old_ac_status: '0',
             new_ac_status: '0',
             gamma_values: Box::new(gamma_values),
         };
 
-        let gamma = calc_new_brightness(&test_info);
+        let gamma = calc_new_brightness(&test_info, &test_info.gamma_values, 255);
 
         assert_eq!(gamma, 100);
     }
     #[test]
     fn test_new_gamma_empty() {
         let gamma_values: Config = Config {
-            full: 200,
-            low: 100,
+            full: Level::Raw(200),
+            low: Level::Raw(100),
             low_perc: 24,
-            charging: 200,
-            discharging: 155,
-            unknown: 155,
-            ac_in: 200,
+            charging: Level::Raw(200),
+            discharging: Level::Raw(155),
+            unknown: Level::Raw(155),
+            ac_in: Level::Raw(200),
+            ..Default::default()
         };
 
         let test_info: BatteryInfo = BatteryInfo {
             soc: 0.0,
-            old_status: State::Unknown,
+            policy: Policy::default(),
             new_status: State::Empty,
-            old_ac_status: '0',
             new_ac_status: '0',
             gamma_values: Box::new(gamma_values),
         };
 
-        let gamma = calc_new_brightness(&test_info);
+        let gamma = calc_new_brightness(&test_info, &test_info.gamma_values, 255);
 
         assert_eq!(gamma, 100);
     }
+
+    #[test]
+    fn test_new_gamma_rules() {
+        let mut gamma_values: Config = Config {
+            full: Level::Raw(200),
+            low: Level::Raw(100),
+            low_perc: 20,
+            charging: Level::Raw(255),
+            discharging: Level::Raw(155),
+            unknown: Level::Raw(120),
+            ac_in: Level::Raw(210),
+            ..Default::default()
+        };
+        gamma_values.rules.unknown.battery = Target::Unknown;
+        gamma_values.rules.full.ac = Target::AcIn;
+        gamma_values.rules.charging.battery = Target::Draining;
+
+        let mut test_info: BatteryInfo = BatteryInfo {
+            soc: 0.1,
+            policy: Policy::default(),
+            new_status: State::Unknown,
+            new_ac_status: '0',
+            gamma_values: Box::new(gamma_values),
+        };
+        assert_eq!(
+            calc_new_brightness(&test_info, &test_info.gamma_values, 255),
+            120
+        );
+
+        test_info.new_status = State::Full;
+        test_info.new_ac_status = '1';
+        assert_eq!(
+            calc_new_brightness(&test_info, &test_info.gamma_values, 255),
+            210
+        );
+        assert!(!draining(&test_info));
+
+        // charging without AC, i.e: a battery that reports the wrong state, follows the charge
+        test_info.new_status = State::Charging;
+        test_info.new_ac_status = '0';
+        assert_eq!(
+            calc_new_brightness(&test_info, &test_info.gamma_values, 255),
+            100
+        );
+        assert!(draining(&test_info));
+    }
+
+    // waits up to 5 seconds for the fake backlight to reach brightness
+    fn wait_for_brightness(fixture: &Fixture, brightness: u32) {
+        for _ in 0..50 {
+            if fixture.brightness("intel_backlight") == brightness {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(fixture.brightness("intel_backlight"), brightness);
+    }
+
+    // sends request to the daemon at socket, giving it up to 5 seconds to start listening
+    fn request(socket: &std::path::Path, request: Request) -> Response {
+        for _ in 0..50 {
+            if let Ok(response) = control::send_to(socket, &request) {
+                return response;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        control::send_to(socket, &request).unwrap()
+    }
+
+    // a daemon driving the fixture's backlights with config, and listening on socket
+    fn start<B, P, C>(
+        fixture: &Fixture,
+        name: &str,
+        config: &str,
+        batteries: B,
+        power: P,
+        clock: C,
+    ) -> (std::thread::JoinHandle<Result<(), error::Error>>, PathBuf)
+    where
+        B: BatterySource + Send + 'static,
+        P: PowerSource + Send + 'static,
+        C: Clock + Send + 'static,
+    {
+        let temp = |ext: &str| {
+            std::env::temp_dir().join(format!(
+                "gamma_daemon_{}_{}.{}",
+                name,
+                std::process::id(),
+                ext
+            ))
+        };
+        let (config_path, socket) = (temp("toml"), temp("sock"));
+        std::fs::write(&config_path, config).unwrap();
+
+        let devices = fixture.sysfs.backlights().unwrap();
+        let options = RunOptions {
+            config_paths: vec![config_path],
+            config: None,
+            foreground: true,
+            control_socket: Some(socket.clone()),
+            record: None,
+        };
+        let daemon = std::thread::spawn(move || {
+            let result = run(&devices, batteries, power, clock, &options);
+            let _ = std::fs::remove_file(&options.config_paths[0]);
+            result
+        });
+        (daemon, socket)
+    }
+
+    #[test]
+    fn test_run_on_fake_sysfs() {
+        let fixture = Fixture::new("run")
+            .backlight("intel_backlight", 500, 1000)
+            .battery("BAT0", "Discharging", 80)
+            .ac("AC", false);
+        let batteries = SysfsBatteries::new(&fixture.sysfs).unwrap();
+        let (daemon, socket) = start(
+            &fixture,
+            "run",
+            "charging = 900\ndischarging = 400\nlow_perc = 10\npoll_interval = 1\n",
+            batteries,
+            fixture.sysfs.clone(),
+            SystemClock,
+        );
+
+        wait_for_brightness(&fixture, 400);
+
+        // plug the charger in
+        fixture.set("power_supply", "BAT0", "status", "Charging");
+        fixture.set("power_supply", "AC", "online", "1");
+        wait_for_brightness(&fixture, 900);
+
+        assert!(request(&socket, Request::Quit).ok);
+        daemon.join().unwrap().unwrap();
+        // put back to what it was before the daemon started
+        assert_eq!(fixture.brightness("intel_backlight"), 500);
+    }
+
+    // one battery and a charger, which the test changes while the daemon runs
+    #[derive(Clone)]
+    struct MockPower(Arc<Mutex<(Reading, bool)>>);
+
+    impl MockPower {
+        fn new(state: State, soc: f32, ac: bool) -> Self {
+            let power = MockPower(Arc::new(Mutex::new((
+                Reading {
+                    model: None,
+                    serial_number: None,
+                    state,
+                    energy: 0.0,
+                    energy_full: 0.0,
+                    state_of_charge: 0.0,
+                },
+                false,
+            ))));
+            power.set(state, soc, ac);
+            power
+        }
+
+        fn set(&self, state: State, soc: f32, ac: bool) {
+            let mut power = self.0.lock().unwrap();
+            power.0.state = state;
+            power.0.energy = soc * 50000.0;
+            power.0.energy_full = 50000.0;
+            power.0.state_of_charge = soc;
+            power.1 = ac;
+        }
+    }
+
+    impl BatterySource for MockPower {
+        fn scan(&mut self) -> Result<(), battery::Error> {
+            Ok(())
+        }
+
+        fn refresh(&mut self) -> Result<(), battery::Error> {
+            Ok(())
+        }
+
+        fn readings(&self) -> Vec<Reading> {
+            vec![self.0.lock().unwrap().0.clone()]
+        }
+    }
+
+    impl PowerSource for MockPower {
+        fn supplies(&self, _pinned: Option<&str>) -> io::Result<Vec<String>> {
+            Ok(vec!["AC".to_string()])
+        }
+
+        fn ac_online(&self, _pinned: Option<&str>) -> bool {
+            self.0.lock().unwrap().1
+        }
+    }
+
+    #[test]
+    fn test_run_state_transitions() {
+        let fixture = Fixture::new("transitions").backlight("intel_backlight", 500, 1000);
+        let power = MockPower::new(State::Discharging, 0.8, false);
+        let clock = ManualClock::new();
+        let (daemon, socket) = start(
+            &fixture,
+            "transitions",
+            "full = 1000\nlow = 100\nlow_perc = 20\ncharging = 900\ndischarging = 400\nac_in = 950\npoll_interval = 60\n",
+            power.clone(),
+            power.clone(),
+            clock.clone(),
+        );
+        clock.run_for(Duration::ZERO);
+
+        // what the daemon sets once it has looked at the batteries again
+        let brightness = |state: State, soc: f32, ac: bool| {
+            power.set(state, soc, ac);
+            clock.run_for(Duration::from_secs(60));
+            fixture.brightness("intel_backlight")
+        };
+
+        assert_eq!(fixture.brightness("intel_backlight"), 400);
+        // plugged in, charged up, and unplugged again
+        assert_eq!(brightness(State::Charging, 0.8, true), 900);
+        assert_eq!(brightness(State::Full, 1.0, true), 1000);
+        assert_eq!(brightness(State::Discharging, 1.0, false), 400);
+        // drained below low_perc, and further
+        assert_eq!(brightness(State::Discharging, 0.15, false), 100);
+        assert_eq!(brightness(State::Discharging, 0.1, false), 100);
+        // the charge reading higher again stays low, even when the brightness is set again
+        assert_eq!(brightness(State::Discharging, 0.21, false), 100);
+        assert!(request(&socket, Request::Reload).ok);
+        assert_eq!(fixture.brightness("intel_backlight"), 100);
+        assert!(request(&socket, Request::Pause).ok);
+        assert!(request(&socket, Request::Resume).ok);
+        assert_eq!(fixture.brightness("intel_backlight"), 100);
+        // plugged in while low, and sitting on AC without charging
+        assert_eq!(brightness(State::Charging, 0.1, true), 900);
+        assert_eq!(brightness(State::Unknown, 0.1, true), 950);
+
+        let status = request(&socket, Request::Status).status.unwrap();
+        assert_eq!(status.battery_state, "unknown");
+        assert!(status.ac_online);
+
+        assert!(request(&socket, Request::Quit).ok);
+        daemon.join().unwrap().unwrap();
+        assert_eq!(fixture.brightness("intel_backlight"), 500);
+    }
+
+    #[test]
+    fn test_run_on_manual_clock() {
+        let fixture = Fixture::new("clock").backlight("intel_backlight", 500, 1000);
+        let power = MockPower::new(State::Discharging, 1.0, false);
+        let clock = ManualClock::new();
+        let (daemon, socket) = start(
+            &fixture,
+            "clock",
+            "poll_interval = 60\noverride_timeout = 1800\n[curve]\n100 = 1000\n0 = 0\n[fade]\nduration_ms = 2000\n",
+            power.clone(),
+            power.clone(),
+            clock.clone(),
+        );
+        let minute = Duration::from_secs(60);
+
+        // the first fade takes 2 seconds of the clock, however long it takes for real
+        clock.run_for(Duration::from_secs(1));
+        let halfway = fixture.brightness("intel_backlight");
+        assert!(halfway > 500 && halfway < 1000);
+        clock.run_for(Duration::from_secs(1));
+        assert_eq!(fixture.brightness("intel_backlight"), 1000);
+
+        // five hours of losing 1% every 3 minutes, with the brightness following the curve
+        for step in 1..=100 {
+            power.set(State::Discharging, 1.0 - step as f32 / 100.0, false);
+            clock.run_for(3 * minute);
+            if step % 20 == 0 {
+                assert_eq!(fixture.brightness("intel_backlight"), 1000 - step * 10);
+            }
+        }
+        assert_eq!(clock.elapsed(), Duration::from_secs(2) + 300 * minute);
+
+        // brightness keys, left alone for the override_timeout and then taken back
+        power.set(State::Discharging, 0.5, false);
+        fixture.set("backlight", "intel_backlight", "brightness", "800");
+        clock.run_for(minute);
+        assert_eq!(fixture.brightness("intel_backlight"), 800);
+        clock.run_for(29 * minute);
+        assert_eq!(fixture.brightness("intel_backlight"), 800);
+        // it was noticed on the next loop, a minute after it happened
+        clock.run_for(minute + Duration::from_secs(2));
+        assert_eq!(fixture.brightness("intel_backlight"), 500);
+
+        assert!(request(&socket, Request::Quit).ok);
+        daemon.join().unwrap().unwrap();
+        assert_eq!(fixture.brightness("intel_backlight"), 500);
+    }
 }
@@ -0,0 +1,157 @@
+/*  Contains a client for systemd's notify protocol (see sd_notify(3)).
+ *
+ *  When systemd starts a Type=notify service it sets $NOTIFY_SOCKET, and the service tells it
+ *  how it's doing by sending datagrams like "READY=1" there. If the unit has WatchdogSec set,
+ *  systemd also sets $WATCHDOG_USEC and restarts the service if it doesn't send "WATCHDOG=1"
+ *  often enough.
+ *
+ * */
+
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::time::{Duration, Instant};
+
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    // how often to send WATCHDOG=1, if systemd wants it
+    watchdog: Option<Duration>,
+    last_watchdog: Option<Instant>,
+}
+
+impl Notifier {
+    /* Returns a Notifier for the socket in $NOTIFY_SOCKET, or None if we weren't started by
+     * systemd as a notify service.
+     * */
+    pub fn from_env() -> Option<Self> {
+        let path = env::var("NOTIFY_SOCKET").ok()?;
+        let watchdog = watchdog_interval(
+            env::var("WATCHDOG_USEC").ok().as_deref(),
+            env::var("WATCHDOG_PID").ok().as_deref(),
+        );
+
+        match Notifier::open(&path, watchdog) {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                eprintln!("Can't talk to systemd on {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    // a path starting with '@' is in the abstract namespace, like systemd uses
+    fn open(path: &str, watchdog: Option<Duration>) -> io::Result<Self> {
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+
+        Ok(Notifier {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            watchdog,
+            last_watchdog: None,
+        })
+    }
+
+    pub fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            eprintln!("Error sending {} to systemd: {}", state, e);
+        }
+    }
+
+    /* Sends WATCHDOG=1 if half the watchdog interval has passed since the last one.
+     * Returns how long until it should be called again, or None if there is no watchdog.
+     * */
+    pub fn keep_alive(&mut self, now: Instant) -> Option<Duration> {
+        let interval = self.watchdog? / 2;
+
+        let due = match self.last_watchdog {
+            Some(last) => now.duration_since(last) >= interval,
+            None => true,
+        };
+        if due {
+            self.notify("WATCHDOG=1");
+            self.last_watchdog = Some(now);
+            return Some(interval);
+        }
+
+        self.last_watchdog
+            .map(|last| (last + interval).saturating_duration_since(now))
+    }
+}
+
+/* Parses $WATCHDOG_USEC. systemd only sets $WATCHDOG_PID when the watchdog is meant for one
+ * process, in which case it has to be us.
+ * */
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != process::id() {
+            return None;
+        }
+    }
+
+    match usec?.parse::<u64>().ok()? {
+        0 => None,
+        usec => Some(Duration::from_micros(usec)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_interval() {
+        let me = process::id().to_string();
+
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some(&me)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(watchdog_interval(Some("30000000"), Some("1")), None);
+        assert_eq!(watchdog_interval(Some("0"), None), None);
+        assert_eq!(watchdog_interval(Some("soon"), None), None);
+        assert_eq!(watchdog_interval(None, None), None);
+    }
+
+    #[test]
+    fn test_notify() {
+        let path = std::env::temp_dir().join(format!("gamma_daemon_notify_{}.sock", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd.set_nonblocking(true).unwrap();
+
+        let mut notifier =
+            Notifier::open(path.to_str().unwrap(), Some(Duration::from_secs(10))).unwrap();
+        notifier.notify("READY=1");
+
+        let mut buf = [0u8; 64];
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        // the first keep_alive always pings, then not again until half the interval has passed
+        let start = Instant::now();
+        assert_eq!(notifier.keep_alive(start), Some(Duration::from_secs(5)));
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+
+        let soon = start + Duration::from_secs(2);
+        assert_eq!(notifier.keep_alive(soon), Some(Duration::from_secs(3)));
+        assert!(systemd.recv(&mut buf).is_err());
+
+        let later = start + Duration::from_secs(5);
+        assert_eq!(notifier.keep_alive(later), Some(Duration::from_secs(5)));
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,510 @@
+/*  Contains the policy state machine, which decides when the brightness should change.
+ *
+ *  Every loop the daemon feeds it what the batteries and the AC say, and it moves to the Phase that
+ *  matches. Entering a phase sets the brightness the [rules] pick for the battery's state and the
+ *  AC (see config::rules), and staying in one leaves the brightness alone, apart from following a
+ *  [curve] as the battery drains.
+ *
+ *  Low is the one phase that doesn't just follow the readings. It is entered when the battery
+ *  drains to low_perc, and is only left when the battery's state or the AC changes, so a charge
+ *  that wobbles around low_perc doesn't set the brightness back and forth. The lowest charge read
+ *  since the power last changed is kept for this, and low_or_discharging picks low from it too, so
+ *  the brightness stays low whenever it is set again, i.e: on a reload.
+ *
+ *  [[device]] sections can each have their own low_perc, so Low is entered at the highest one, and
+ *  the brightness is set again every time the battery drains to another one.
+ *
+ *  On top of the Phase, the Mode says who the brightness is left to. Changing it by hand puts the
+ *  machine in Override, and pausing it over the control socket in Paused. Readings still move the
+ *  Phase along in either, so leaving them sets whatever the batteries say by then.
+ *
+ * */
+
+use crate::daemon::config::rules::Target;
+use crate::daemon::config::Config;
+use battery::State;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Phase {
+    // nothing has been read yet
+    #[default]
+    Starting,
+    // plugged in with the battery neither charging nor full, i.e: sitting on AC, or no battery
+    Ac,
+    Charging,
+    Full,
+    // the battery is draining, or unplugged in a state we can't tell
+    Discharging,
+    // draining at or below low_perc
+    Low,
+    // the battery says it's empty
+    Critical,
+}
+
+/* Who the brightness is left to */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    // the daemon sets every device
+    #[default]
+    Auto,
+    // some devices were changed by hand, see Fader::check_override. Those are left alone until
+    // override_timeout runs out or the battery's state or the AC changes, the rest are still set.
+    Override,
+    // paused over the control socket, nothing is set until it is resumed
+    Paused,
+}
+
+/* What to do about the brightness after a transition */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    // leave it alone
+    Stay,
+    // set what the phase's rule picks on every device not changed by hand. This is the entry
+    // action of every phase, and of Auto when an override runs out.
+    Apply,
+    // the rule follows the battery's charge, so set it again if a [curve] moved, see curve_moved
+    Follow,
+    // hand the devices changed by hand back, then set them all, i.e: on leaving Override
+    // because the power changed, or on leaving Paused
+    Reclaim,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Policy {
+    pub phase: Phase,
+    pub mode: Mode,
+    // the last reading, which the [rules] are looked up with
+    pub state: State,
+    pub ac: bool,
+    // the lowest charge read since the battery's state or the AC last changed
+    pub lowest: f32,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            phase: Phase::Starting,
+            mode: Mode::Auto,
+            state: State::Unknown,
+            ac: false,
+            lowest: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transition {
+    pub from: Policy,
+    pub to: Policy,
+    pub action: Action,
+}
+
+impl Policy {
+    /* Returns where the batteries being in state, with the AC plugged in or not and soc charge
+     * left, takes the machine, and what to do about the brightness on the way. devices are the
+     * names of the backlights being driven, which decide the low_perc that counts.
+     * */
+    pub fn next(
+        &self,
+        state: State,
+        ac: bool,
+        soc: f32,
+        config: &Config,
+        devices: &[&str],
+    ) -> Transition {
+        let to_power = self.phase == Phase::Starting || state != self.state || ac != self.ac;
+        let lowest = match to_power {
+            true => soc,
+            false => self.lowest.min(soc),
+        };
+        let target = config.rules.target(state, ac);
+        let crossed = match target {
+            Target::Draining => lows_crossed(config, devices, lowest),
+            _ => 0,
+        };
+        let low = crossed > 0;
+
+        let phase = match state {
+            State::Empty => Phase::Critical,
+            _ if low => Phase::Low,
+            State::Charging => Phase::Charging,
+            State::Full => Phase::Full,
+            State::Discharging => Phase::Discharging,
+            // State::Unknown, and any state the battery crate adds later
+            _ if ac => Phase::Ac,
+            _ => Phase::Discharging,
+        };
+
+        let entered =
+            to_power || phase != self.phase || crossed > lows_crossed(config, devices, self.lowest);
+        let (mode, action) = match self.mode {
+            Mode::Paused => (Mode::Paused, Action::Stay),
+            // the power changing hands the brightness back from the user
+            Mode::Override if to_power => (Mode::Auto, Action::Reclaim),
+            mode if entered => (mode, Action::Apply),
+            mode if target == Target::Draining => (mode, Action::Follow),
+            mode => (mode, Action::Stay),
+        };
+
+        Transition {
+            from: *self,
+            to: Policy {
+                phase,
+                mode,
+                state,
+                ac,
+                lowest,
+            },
+            action,
+        }
+    }
+
+    /* Returns where the overrides check_overrides found take the machine. held is true if any
+     * device is changed by hand now, and expired if override_timeout handed some back.
+     * */
+    pub fn overrides(&self, held: bool, expired: bool) -> Transition {
+        let (mode, action) = match (self.mode, held) {
+            (Mode::Paused, _) => (Mode::Paused, Action::Stay),
+            (_, true) if expired => (Mode::Override, Action::Apply),
+            (_, true) => (Mode::Override, Action::Stay),
+            (_, false) if expired => (Mode::Auto, Action::Apply),
+            (_, false) => (Mode::Auto, Action::Stay),
+        };
+        self.to(mode, action)
+    }
+
+    pub fn pause(&self) -> Transition {
+        self.to(Mode::Paused, Action::Stay)
+    }
+
+    // resuming hands every device back, even when the daemon wasn't paused
+    pub fn resume(&self) -> Transition {
+        self.to(Mode::Auto, Action::Reclaim)
+    }
+
+    // a new config is applied right away, unless the daemon is paused
+    pub fn reload(&self) -> Transition {
+        match self.mode {
+            Mode::Paused => self.to(Mode::Paused, Action::Stay),
+            mode => self.to(mode, Action::Apply),
+        }
+    }
+
+    fn to(&self, mode: Mode, action: Action) -> Transition {
+        Transition {
+            from: *self,
+            to: Policy { mode, ..*self },
+            action,
+        }
+    }
+}
+
+/* Returns how many of the devices go to low at a charge of soc, each by the brightness table
+ * Config::for_device gives it. A [curve] replaces low and low_perc, so tables with one never do.
+ * */
+fn lows_crossed(config: &Config, devices: &[&str], soc: f32) -> usize {
+    devices
+        .iter()
+        .map(|name| config.for_device(name))
+        .filter(|table| table.curve.is_none() && soc <= (table.low_perc as f32) / 100.0)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::config::rules::Rule;
+    use crate::daemon::config::DeviceConfig;
+
+    // the backlights the policy is deciding for
+    const DEVICES: [&str; 1] = ["intel_backlight"];
+
+    // every kind of reading, as (state, ac, soc), with the config below
+    const READINGS: [(State, bool, f32); 12] = [
+        (State::Charging, true, 0.5),
+        (State::Charging, true, 0.1),
+        (State::Charging, false, 0.5),
+        (State::Full, true, 1.0),
+        (State::Full, false, 1.0),
+        (State::Unknown, true, 0.8),
+        (State::Unknown, false, 0.8),
+        (State::Unknown, false, 0.1),
+        (State::Discharging, false, 0.5),
+        (State::Discharging, false, 0.1),
+        (State::Discharging, true, 0.5),
+        (State::Empty, false, 0.0),
+    ];
+
+    fn config() -> Config {
+        Config {
+            low_perc: 20,
+            ..Default::default()
+        }
+    }
+
+    // the machine after a single reading
+    fn after(reading: (State, bool, f32), config: &Config) -> Policy {
+        let (state, ac, soc) = reading;
+        Policy::default().next(state, ac, soc, config, &DEVICES).to
+    }
+
+    #[test]
+    fn test_phases() {
+        let config = config();
+        let phases: Vec<Phase> = READINGS.iter().map(|r| after(*r, &config).phase).collect();
+
+        use Phase::*;
+        assert_eq!(
+            phases,
+            vec![
+                Charging,
+                Charging,
+                Charging,
+                Full,
+                Full,
+                Ac,
+                Discharging,
+                // unknown unplugged isn't draining by default, so it's never low
+                Discharging,
+                Discharging,
+                Low,
+                Discharging,
+                Critical,
+            ]
+        );
+
+        // the first reading always sets the brightness, whatever it is
+        for (state, ac, soc) in READINGS {
+            let transition = Policy::default().next(state, ac, soc, &config, &DEVICES);
+            assert_eq!(transition.action, Action::Apply);
+        }
+    }
+
+    #[test]
+    fn test_every_transition() {
+        let config = config();
+
+        for from in READINGS {
+            for to in READINGS {
+                let policy = after(from, &config);
+                let (state, ac, soc) = to;
+                let transition = policy.next(state, ac, soc, &config, &DEVICES);
+                let power_changed = from.0 != to.0 || from.1 != to.1;
+                assert_eq!(transition.to.mode, Mode::Auto);
+
+                // with the power the same, Low is the only phase that can be entered, and it is
+                // only left by the power changing
+                let expected = match power_changed {
+                    true => after(to, &config).phase,
+                    false if policy.phase == Phase::Low => Phase::Low,
+                    false => after(to, &config).phase,
+                };
+                assert_eq!(transition.to.phase, expected, "{:?} to {:?}", from, to);
+
+                // entering a phase, or the power changing, sets the brightness
+                let action = match (power_changed, transition.to.phase != policy.phase) {
+                    (false, false) => match config.rules.target(state, ac) {
+                        Target::Draining => Action::Follow,
+                        _ => Action::Stay,
+                    },
+                    _ => Action::Apply,
+                };
+                assert_eq!(transition.action, action, "{:?} to {:?}", from, to);
+
+                // with a device changed by hand, the phases move the same way, and the power
+                // changing hands it back
+                let held = policy.overrides(true, false).to;
+                let transition = held.next(state, ac, soc, &config, &DEVICES);
+                assert_eq!(transition.to.phase, expected);
+                let (mode, action) = match power_changed {
+                    true => (Mode::Auto, Action::Reclaim),
+                    false => (Mode::Override, action),
+                };
+                assert_eq!((transition.to.mode, transition.action), (mode, action));
+
+                // while paused they move too, but nothing is set
+                let paused = policy.pause().to;
+                let transition = paused.next(state, ac, soc, &config, &DEVICES);
+                assert_eq!(transition.to.phase, expected);
+                assert_eq!(
+                    (transition.to.mode, transition.action),
+                    (Mode::Paused, Action::Stay)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_modes() {
+        let policy = after((State::Discharging, false, 0.5), &config());
+        let step = |transition: Transition| (transition.to.mode, transition.action);
+
+        use Action::*;
+        use Mode::*;
+        // every mode, and what each input does in it
+        let modes = [policy, policy.overrides(true, false).to, policy.pause().to];
+        let expected = [
+            // overrides(true, false), overrides(true, true), overrides(false, true),
+            // overrides(false, false), pause, resume, reload
+            [
+                (Override, Stay),
+                (Override, Apply),
+                (Auto, Apply),
+                (Auto, Stay),
+                (Paused, Stay),
+                (Auto, Reclaim),
+                (Auto, Apply),
+            ],
+            [
+                (Override, Stay),
+                (Override, Apply),
+                (Auto, Apply),
+                (Auto, Stay),
+                (Paused, Stay),
+                (Auto, Reclaim),
+                (Override, Apply),
+            ],
+            [
+                (Paused, Stay),
+                (Paused, Stay),
+                (Paused, Stay),
+                (Paused, Stay),
+                (Paused, Stay),
+                (Auto, Reclaim),
+                (Paused, Stay),
+            ],
+        ];
+
+        for (policy, expected) in modes.iter().zip(expected) {
+            let got = [
+                step(policy.overrides(true, false)),
+                step(policy.overrides(true, true)),
+                step(policy.overrides(false, true)),
+                step(policy.overrides(false, false)),
+                step(policy.pause()),
+                step(policy.resume()),
+                step(policy.reload()),
+            ];
+            assert_eq!(got, expected, "in {:?}", policy.mode);
+        }
+
+        // none of them touch the phase
+        let paused = policy.pause().to;
+        assert_eq!(paused.phase, Phase::Discharging);
+        assert_eq!(paused.resume().to.phase, Phase::Discharging);
+    }
+
+    #[test]
+    fn test_low() {
+        let config = config();
+        let mut policy = Policy::default();
+        let mut step = |state: State, ac: bool, soc: f32| {
+            let transition = policy.next(state, ac, soc, &config, &DEVICES);
+            policy = transition.to;
+            (transition.to.phase, transition.action)
+        };
+
+        use Action::*;
+        assert_eq!(
+            step(State::Discharging, false, 0.3),
+            (Phase::Discharging, Apply)
+        );
+        assert_eq!(
+            step(State::Discharging, false, 0.25),
+            (Phase::Discharging, Follow)
+        );
+        // low_perc itself counts as low, like low_or_discharging
+        assert_eq!(step(State::Discharging, false, 0.2), (Phase::Low, Apply));
+        assert_eq!(step(State::Discharging, false, 0.15), (Phase::Low, Follow));
+        // the charge reading a bit higher again doesn't leave Low
+        assert_eq!(step(State::Discharging, false, 0.21), (Phase::Low, Follow));
+
+        // charging and unplugging again while low is low straight away
+        assert_eq!(step(State::Charging, true, 0.15), (Phase::Charging, Apply));
+        assert_eq!(step(State::Charging, true, 0.3), (Phase::Charging, Stay));
+        assert_eq!(step(State::Discharging, false, 0.15), (Phase::Low, Apply));
+        // and charged past low_perc before unplugging isn't
+        assert_eq!(step(State::Charging, true, 0.15), (Phase::Charging, Apply));
+        assert_eq!(
+            step(State::Discharging, false, 0.3),
+            (Phase::Discharging, Apply)
+        );
+        assert_eq!(step(State::Discharging, false, 0.19), (Phase::Low, Apply));
+        assert_eq!(step(State::Empty, false, 0.0), (Phase::Critical, Apply));
+    }
+
+    #[test]
+    fn test_low_follows_rules() {
+        let mut config = config();
+
+        // a curve replaces low_perc
+        config.curve = toml::from_str::<Config>("[curve]\n100 = 200\n0 = 0\n")
+            .unwrap()
+            .curve;
+        assert_eq!(
+            after((State::Discharging, false, 0.1), &config).phase,
+            Phase::Discharging
+        );
+
+        // any state whose rule drains can be low, and one that doesn't never is
+        config.curve = None;
+        config.rules.unknown.battery = Target::Draining;
+        config.rules.discharging = Rule {
+            ac: Target::Discharging,
+            battery: Target::Discharging,
+        };
+        assert_eq!(
+            after((State::Unknown, false, 0.1), &config).phase,
+            Phase::Low
+        );
+        assert_eq!(
+            after((State::Discharging, false, 0.1), &config).phase,
+            Phase::Discharging
+        );
+    }
+
+    #[test]
+    fn test_device_low_perc() {
+        let mut config = config();
+        let section = |name: &str, low_perc| DeviceConfig {
+            name: name.to_string(),
+            low_perc: Some(low_perc),
+            ..Default::default()
+        };
+        config.device = vec![
+            // no backlight is called that, so it doesn't count
+            section("acpi_video*", 60),
+            section("intel_backlight", 40),
+            // intel_backlight already matched the section above
+            section("intel_*", 50),
+            section("amdgpu_bl0", 10),
+        ];
+        let devices = ["intel_backlight", "amdgpu_bl0"];
+        let mut policy = Policy::default();
+        let mut step = |soc: f32| {
+            let transition = policy.next(State::Discharging, false, soc, &config, &devices);
+            policy = transition.to;
+            (transition.to.phase, transition.action)
+        };
+
+        // every low_perc the battery drains to sets the brightness again
+        use Action::*;
+        assert_eq!(step(0.7), (Phase::Discharging, Apply));
+        assert_eq!(step(0.55), (Phase::Discharging, Follow));
+        assert_eq!(step(0.45), (Phase::Discharging, Follow));
+        assert_eq!(step(0.4), (Phase::Low, Apply));
+        assert_eq!(step(0.3), (Phase::Low, Follow));
+        assert_eq!(step(0.15), (Phase::Low, Follow));
+        assert_eq!(step(0.1), (Phase::Low, Apply));
+        assert_eq!(step(0.05), (Phase::Low, Follow));
+
+        // a section with a curve never goes to low
+        config.device[1].curve = toml::from_str::<Config>("[curve]\n100 = 200\n0 = 0\n")
+            .unwrap()
+            .curve;
+        assert_eq!(
+            after((State::Discharging, false, 0.3), &config).phase,
+            Phase::Discharging
+        );
+    }
+}
@@ -0,0 +1,106 @@
+/*  Contains functions to find the notebook's AC adapter(s).
+ *
+ *  Adapters have different names on different machines (AC, ADP1, ACAD, AC0, ucsi-source-psy-*...),
+ *  so instead of reading a fixed file we look through every power supply in /sys/class/power_supply
+ *  and use the ones whose type is Mains or USB. If any of them is online, the notebook is plugged in.
+ *
+ * */
+
+use crate::daemon::read_file;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// power supply types that can charge the notebook
+const AC_TYPES: [&str; 2] = ["Mains", "USB"];
+
+/* Returns the directories of every AC supply in dir.
+ *
+ * If pinned is set, only the supply with that name is returned, as long as it exists.
+ * */
+pub fn find_ac_supplies(dir: &Path, pinned: Option<&str>) -> io::Result<Vec<PathBuf>> {
+    if let Some(name) = pinned {
+        let supply = dir.join(name);
+        return Ok(if supply.is_dir() {
+            vec![supply]
+        } else {
+            Vec::new()
+        });
+    }
+
+    let mut supplies = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let supply = entry?.path();
+        let supply_type = match read_file::get_contents(&supply.join("type").to_string_lossy()) {
+            Ok(t) => t,
+            Err(_) => continue, // not every entry has a type, skip those
+        };
+
+        if AC_TYPES.contains(&supply_type.trim()) {
+            supplies.push(supply);
+        }
+    }
+
+    supplies.sort();
+    Ok(supplies)
+}
+
+/* Returns true if any AC supply in dir is online.
+ *
+ * Supplies we can't read are treated as offline, and so is a machine with no AC supplies at all.
+ * */
+pub fn ac_online(dir: &Path, pinned: Option<&str>) -> bool {
+    let supplies = find_ac_supplies(dir, pinned).unwrap_or_default();
+
+    supplies.iter().any(|supply| {
+        match read_file::get_contents(&supply.join("online").to_string_lossy()) {
+            Ok(online) => online.trim() == "1",
+            Err(_) => false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::sysfs::fixture::Fixture;
+
+    #[test]
+    fn test_find_ac_supplies() {
+        let fixture = Fixture::new("find_ac_supplies")
+            .battery("BAT0", "Discharging", 80)
+            .ac("ADP1", false)
+            .usb("ucsi-source-psy-USBC000:001", false)
+            .battery("hidpp_battery_0", "Discharging", 20);
+        let dir = fixture.sysfs.power_supply_dir();
+
+        let supplies = find_ac_supplies(&dir, None).unwrap();
+        assert_eq!(
+            supplies,
+            vec![dir.join("ADP1"), dir.join("ucsi-source-psy-USBC000:001")]
+        );
+
+        let pinned = find_ac_supplies(&dir, Some("ADP1")).unwrap();
+        assert_eq!(pinned, vec![dir.join("ADP1")]);
+
+        assert!(find_ac_supplies(&dir, Some("AC")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ac_online() {
+        let fixture = Fixture::new("ac_online")
+            .battery("BAT0", "Discharging", 80)
+            .ac("ACAD", false)
+            .usb("ucsi-source-psy-USBC000:001", true);
+        let dir = fixture.sysfs.power_supply_dir();
+
+        // plugged in through USB-C only
+        assert!(ac_online(&dir, None));
+        assert!(!ac_online(&dir, Some("ACAD")));
+        assert!(ac_online(&dir, Some("ucsi-source-psy-USBC000:001")));
+
+        // no such supply, so not plugged
+        assert!(!ac_online(&dir, Some("AC")));
+        assert!(!ac_online(&dir.join("missing"), None));
+    }
+}
@@ -0,0 +1,100 @@
+/*  Contains a signalfd, which turns signals sent to the daemon into something the main loop can
+ *  poll for alongside uevents and the config watch, instead of handling them in a signal handler.
+ *
+ * */
+
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+pub struct SignalFd {
+    fd: RawFd,
+}
+
+impl SignalFd {
+    /* Blocks the given signals, so they are no longer delivered the usual way, and returns a
+     * SignalFd to read them from instead.
+     * */
+    pub fn open(signals: &[libc::c_int]) -> io::Result<Self> {
+        // SAFETY: the sigset is initialised by sigemptyset before use, and the fd is owned by the
+        // returned SignalFd
+        unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for signal in signals {
+                libc::sigaddset(&mut set, *signal);
+            }
+
+            if libc::sigprocmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let fd = libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(SignalFd { fd })
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /* Returns every signal waiting on the fd */
+    pub fn read(&self) -> io::Result<Vec<libc::c_int>> {
+        let mut signals = Vec::new();
+
+        loop {
+            // SAFETY: signalfd_siginfo is plain old data, and we read at most its size into it
+            let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+            let size = mem::size_of::<libc::signalfd_siginfo>();
+            let len = unsafe {
+                libc::read(
+                    self.fd,
+                    &mut info as *mut libc::signalfd_siginfo as *mut libc::c_void,
+                    size,
+                )
+            };
+
+            if len < 0 {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(signals),
+                    _ => Err(e),
+                };
+            }
+            if len as usize == size {
+                signals.push(info.ssi_signo as libc::c_int);
+            }
+        }
+    }
+}
+
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        // SAFETY: we own the fd
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_signal() {
+        let signals = SignalFd::open(&[libc::SIGUSR1]).unwrap();
+        assert!(signals.read().unwrap().is_empty());
+
+        // raise sends the signal to this thread, which is the one that blocked it
+        // SAFETY: SIGUSR1 is blocked, so it waits on the signalfd instead of killing the test
+        unsafe {
+            libc::raise(libc::SIGUSR1);
+        }
+        assert_eq!(signals.read().unwrap(), vec![libc::SIGUSR1]);
+    }
+}
@@ -0,0 +1,462 @@
+/*  Contains readers for the sysfs files gamma_daemon uses: the backlights in /sys/class/backlight
+ *  and the batteries in /sys/class/power_supply.
+ *
+ *  The root these are read from can be moved with --sysfs-root or $GAMMA_DAEMON_SYSFS_ROOT, so the
+ *  daemon can be pointed at a fake sysfs tree, i.e: one laid out by the Fixture below in the tests.
+ *
+ * */
+
+use crate::daemon::batteries::Reading;
+use crate::daemon::{power_supply, Backlight, BacklightError, BatterySource, PowerSource};
+use battery::State;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_ROOT: &str = "/sys";
+pub const ROOT_ENV: &str = "GAMMA_DAEMON_SYSFS_ROOT";
+
+/* Where sysfs is mounted, /sys unless we are told otherwise */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sysfs {
+    root: PathBuf,
+}
+
+impl Default for Sysfs {
+    fn default() -> Self {
+        Sysfs::new(DEFAULT_ROOT)
+    }
+}
+
+impl Sysfs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Sysfs { root: root.into() }
+    }
+
+    /* Returns the root given on the command line, then $GAMMA_DAEMON_SYSFS_ROOT, then /sys */
+    pub fn from_args(root: Option<String>) -> Self {
+        match root.or_else(|| env::var(ROOT_ENV).ok().filter(|r| !r.is_empty())) {
+            Some(root) => Sysfs::new(root),
+            None => Sysfs::default(),
+        }
+    }
+
+    // true if this is the real sysfs, so other crates that only know /sys can read it too
+    pub fn is_default(&self) -> bool {
+        self.root == Path::new(DEFAULT_ROOT)
+    }
+
+    pub fn backlight_dir(&self) -> PathBuf {
+        self.root.join("class/backlight")
+    }
+
+    pub fn power_supply_dir(&self) -> PathBuf {
+        self.root.join("class/power_supply")
+    }
+
+    /* Returns every backlight device, sorted by name. No backlight class (i.e: in a container)
+     * is no backlights, rather than an error.
+     * */
+    pub fn backlights(&self) -> Result<Vec<SysfsBacklight>, BacklightError> {
+        let dir = self.backlight_dir();
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut backlights = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            backlights.push(SysfsBacklight::open(dir.join(&name), name)?);
+        }
+
+        backlights.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(backlights)
+    }
+
+    /* Returns every battery in the notebook, sorted by name. Batteries in peripherals, like a
+     * wireless mouse, have scope Device and are skipped.
+     * */
+    pub fn batteries(&self) -> io::Result<Vec<SysfsBattery>> {
+        let dir = self.power_supply_dir();
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut batteries = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let supply = entry?.path();
+            if read_trimmed(&supply.join("type")).ok().as_deref() != Some("Battery")
+                || read_trimmed(&supply.join("scope")).ok().as_deref() == Some("Device")
+            {
+                continue;
+            }
+            batteries.push(SysfsBattery::open(supply)?);
+        }
+
+        batteries.sort_by(|a, b| a.dir.cmp(&b.dir));
+        Ok(batteries)
+    }
+}
+
+/* The AC supplies in power_supply_dir, see power_supply */
+impl PowerSource for Sysfs {
+    fn supplies(&self, pinned: Option<&str>) -> io::Result<Vec<String>> {
+        let supplies = power_supply::find_ac_supplies(&self.power_supply_dir(), pinned)?;
+        Ok(supplies
+            .iter()
+            .filter_map(|s| Some(s.file_name()?.to_string_lossy().into_owned()))
+            .collect())
+    }
+
+    fn ac_online(&self, pinned: Option<&str>) -> bool {
+        power_supply::ac_online(&self.power_supply_dir(), pinned)
+    }
+}
+
+/* The batteries in power_supply_dir, for a sysfs tree the battery crate can't read */
+pub struct SysfsBatteries {
+    sysfs: Sysfs,
+    batteries: Vec<SysfsBattery>,
+}
+
+impl SysfsBatteries {
+    pub fn new(sysfs: &Sysfs) -> io::Result<Self> {
+        Ok(SysfsBatteries {
+            sysfs: sysfs.clone(),
+            batteries: sysfs.batteries()?,
+        })
+    }
+}
+
+impl BatterySource for SysfsBatteries {
+    fn scan(&mut self) -> Result<(), battery::Error> {
+        self.batteries = self.sysfs.batteries()?;
+        Ok(())
+    }
+
+    fn refresh(&mut self) -> Result<(), battery::Error> {
+        for battery in self.batteries.iter_mut() {
+            battery.refresh()?;
+        }
+        Ok(())
+    }
+
+    fn readings(&self) -> Vec<Reading> {
+        self.batteries
+            .iter()
+            .map(|b| Reading {
+                model: b.model.clone(),
+                serial_number: b.serial_number.clone(),
+                state: b.state,
+                energy: b.energy,
+                energy_full: b.energy_full,
+                state_of_charge: b.state_of_charge,
+            })
+            .collect()
+    }
+}
+
+/* A backlight device read straight from its sysfs directory */
+#[derive(Debug, Clone)]
+pub struct SysfsBacklight {
+    name: String,
+    dir: PathBuf,
+    max_brightness: u32,
+}
+
+impl SysfsBacklight {
+    fn open(dir: PathBuf, name: String) -> Result<Self, BacklightError> {
+        let max_brightness = read_u32(&dir.join("max_brightness"))?;
+        Ok(SysfsBacklight {
+            name,
+            dir,
+            max_brightness,
+        })
+    }
+}
+
+impl Backlight for SysfsBacklight {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn max_brightness(&self) -> u32 {
+        self.max_brightness
+    }
+
+    fn brightness(&self) -> Result<u32, BacklightError> {
+        read_u32(&self.dir.join("brightness"))
+    }
+
+    // some drivers don't have actual_brightness, the brightness we set is the best we can do then
+    fn actual_brightness(&self) -> Result<u32, BacklightError> {
+        match read_u32(&self.dir.join("actual_brightness")) {
+            Err(BacklightError::Io(e)) if e.kind() == io::ErrorKind::NotFound => self.brightness(),
+            result => result,
+        }
+    }
+
+    fn change_gamma(&self, gamma: u32) -> Result<(), BacklightError> {
+        if gamma > self.max_brightness {
+            return Err(BacklightError::OutOfRange {
+                given: gamma,
+                max: self.max_brightness,
+            });
+        }
+        fs::write(self.dir.join("brightness"), gamma.to_string())?;
+        Ok(())
+    }
+}
+
+/* A battery read straight from its sysfs directory. The values are read when it is opened, and
+ * again on every refresh.
+ * */
+#[derive(Debug, Clone)]
+pub struct SysfsBattery {
+    dir: PathBuf,
+    pub model: Option<String>,
+    pub serial_number: Option<String>,
+    pub state: State,
+    // µWh, or µAh for batteries that only report charge. Only ever compared to energy_full.
+    pub energy: f32,
+    pub energy_full: f32,
+    // 0.0 to 1.0
+    pub state_of_charge: f32,
+}
+
+impl SysfsBattery {
+    fn open(dir: PathBuf) -> io::Result<Self> {
+        let mut battery = SysfsBattery {
+            model: read_trimmed(&dir.join("model_name")).ok(),
+            serial_number: read_trimmed(&dir.join("serial_number")).ok(),
+            dir,
+            state: State::Unknown,
+            energy: 0.0,
+            energy_full: 0.0,
+            state_of_charge: 0.0,
+        };
+        battery.refresh()?;
+        Ok(battery)
+    }
+
+    pub fn refresh(&mut self) -> io::Result<()> {
+        // the battery is gone, i.e: it was pulled out
+        if !self.dir.is_dir() {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        let value = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| read_trimmed(&self.dir.join(name)).ok()?.parse::<f32>().ok())
+        };
+
+        // "Not charging" and anything else the battery crate doesn't know is Unknown
+        self.state = read_trimmed(&self.dir.join("status"))?
+            .parse()
+            .unwrap_or(State::Unknown);
+        self.energy = value(&["energy_now", "charge_now"]).unwrap_or(0.0);
+        self.energy_full = value(&["energy_full", "charge_full"]).unwrap_or(0.0);
+        self.state_of_charge = match value(&["capacity"]) {
+            Some(capacity) => (capacity / 100.0).clamp(0.0, 1.0),
+            None if self.energy_full > 0.0 => (self.energy / self.energy_full).clamp(0.0, 1.0),
+            None => 0.0,
+        };
+        Ok(())
+    }
+}
+
+fn read_trimmed(path: &Path) -> io::Result<String> {
+    Ok(fs::read_to_string(path)?.trim().to_string())
+}
+
+fn read_u32(path: &Path) -> Result<u32, BacklightError> {
+    Ok(read_trimmed(path)?.parse::<u32>()?)
+}
+
+/* Lays out a fake sysfs tree in a temp dir, for tests that need backlights or batteries.
+ * The tree is removed when the Fixture is dropped.
+ * */
+#[cfg(test)]
+pub mod fixture {
+    use super::*;
+
+    pub struct Fixture {
+        pub sysfs: Sysfs,
+    }
+
+    impl Fixture {
+        // name keeps the trees of tests running at the same time apart
+        pub fn new(name: &str) -> Self {
+            let root = env::temp_dir().join(format!(
+                "gamma_daemon_sysfs_{}_{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+
+            Fixture {
+                sysfs: Sysfs::new(root),
+            }
+        }
+
+        fn write(dir: &Path, files: &[(&str, String)]) {
+            fs::create_dir_all(dir).unwrap();
+            for (name, contents) in files {
+                fs::write(dir.join(name), format!("{}\n", contents)).unwrap();
+            }
+        }
+
+        pub fn backlight(self, name: &str, brightness: u32, max_brightness: u32) -> Self {
+            Fixture::write(
+                &self.sysfs.backlight_dir().join(name),
+                &[
+                    ("brightness", brightness.to_string()),
+                    ("max_brightness", max_brightness.to_string()),
+                ],
+            );
+            self
+        }
+
+        // a battery that reports its charge as a percentage
+        pub fn battery(self, name: &str, status: &str, capacity: u32) -> Self {
+            Fixture::write(
+                &self.sysfs.power_supply_dir().join(name),
+                &[
+                    ("type", "Battery".to_string()),
+                    ("status", status.to_string()),
+                    ("capacity", capacity.to_string()),
+                ],
+            );
+            self
+        }
+
+        pub fn ac(self, name: &str, online: bool) -> Self {
+            self.supply(name, "Mains", online)
+        }
+
+        // a USB-C supply, i.e: ucsi-source-psy-USBC000:001
+        pub fn usb(self, name: &str, online: bool) -> Self {
+            self.supply(name, "USB", online)
+        }
+
+        fn supply(self, name: &str, supply_type: &str, online: bool) -> Self {
+            Fixture::write(
+                &self.sysfs.power_supply_dir().join(name),
+                &[
+                    ("type", supply_type.to_string()),
+                    ("online", u32::from(online).to_string()),
+                ],
+            );
+            self
+        }
+
+        // changes one file of a supply or backlight that is already there, i.e: ("BAT0", "status", "Charging")
+        pub fn set(&self, class: &str, name: &str, file: &str, contents: &str) {
+            let path = self
+                .sysfs
+                .root
+                .join("class")
+                .join(class)
+                .join(name)
+                .join(file);
+            fs::write(path, format!("{}\n", contents)).unwrap();
+        }
+
+        // a daemon running alongside may be halfway through writing it, so an empty file is
+        // read again
+        pub fn brightness(&self, name: &str) -> u32 {
+            let path = self.sysfs.backlight_dir().join(name).join("brightness");
+            for _ in 0..100 {
+                if let Ok(brightness) = read_u32(&path) {
+                    return brightness;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            read_u32(&path).unwrap()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.sysfs.root);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::Fixture;
+    use super::*;
+
+    #[test]
+    fn test_from_args() {
+        assert!(Sysfs::default().is_default());
+        assert_eq!(
+            Sysfs::from_args(Some("/tmp/sys".to_string())).backlight_dir(),
+            PathBuf::from("/tmp/sys/class/backlight")
+        );
+        assert!(!Sysfs::from_args(Some("/tmp/sys".to_string())).is_default());
+    }
+
+    #[test]
+    fn test_backlights() {
+        let fixture = Fixture::new("backlights")
+            .backlight("intel_backlight", 500, 1000)
+            .backlight("acpi_video0", 3, 15);
+
+        let backlights = fixture.sysfs.backlights().unwrap();
+        let names: Vec<&str> = backlights.iter().map(|b| b.name()).collect();
+        assert_eq!(names, vec!["acpi_video0", "intel_backlight"]);
+
+        let intel = &backlights[1];
+        assert_eq!(intel.max_brightness(), 1000);
+        assert_eq!(intel.brightness().unwrap(), 500);
+        // no actual_brightness file, so it's the brightness
+        assert_eq!(intel.actual_brightness().unwrap(), 500);
+
+        intel.change_gamma(750).unwrap();
+        assert_eq!(fixture.brightness("intel_backlight"), 750);
+        assert!(intel.change_gamma(1001).is_err());
+
+        // no backlight class at all
+        assert!(Fixture::new("no_backlights")
+            .sysfs
+            .backlights()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_batteries() {
+        let fixture = Fixture::new("batteries")
+            .battery("BAT0", "Discharging", 80)
+            .ac("AC", false);
+        fixture.set("power_supply", "BAT0", "model_name", "5B10W13975");
+
+        let mut batteries = fixture.sysfs.batteries().unwrap();
+        assert_eq!(batteries.len(), 1);
+        let battery = &mut batteries[0];
+        assert_eq!(battery.model.as_deref(), Some("5B10W13975"));
+        assert_eq!(battery.state, State::Discharging);
+        assert_eq!(battery.state_of_charge, 0.8);
+
+        fixture.set("power_supply", "BAT0", "status", "Not charging");
+        fixture.set("power_supply", "BAT0", "capacity", "79");
+        battery.refresh().unwrap();
+        assert_eq!(battery.state, State::Unknown);
+        assert_eq!(battery.state_of_charge, 0.79);
+
+        fs::remove_dir_all(fixture.sysfs.power_supply_dir().join("BAT0")).unwrap();
+        assert!(battery.refresh().is_err());
+    }
+
+    #[test]
+    fn test_peripheral_batteries() {
+        let fixture = Fixture::new("peripherals").battery("hidpp_battery_0", "Discharging", 20);
+        fixture.set("power_supply", "hidpp_battery_0", "scope", "Device");
+
+        assert!(fixture.sysfs.batteries().unwrap().is_empty());
+    }
+}
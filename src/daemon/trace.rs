@@ -0,0 +1,401 @@
+/*  Contains the traces `run --record` writes and `simulate` replays.
+ *
+ *  A trace is a JSON Lines file with one Sample for every loop of the daemon, i.e:
+ *    {"timestamp_ms":1760000000000,"state":"discharging","soc":0.8,"ac":false}
+ *
+ *  Replaying a trace runs the daemon's own loop on a ManualClock, moved on to each sample's
+ *  timestamp, with batteries and AC that read as the trace says. So a report like "it dimmed at
+ *  the wrong time" can be reproduced from the user's trace and config, without their hardware.
+ *
+ * */
+
+use crate::daemon::batteries::{Batteries, Reading};
+use crate::daemon::clock::manual::ManualClock;
+use crate::daemon::clock::Clock;
+use crate::daemon::config::{BatterySelection, Config};
+use crate::daemon::events::Events;
+use crate::daemon::fade::Fader;
+use crate::daemon::{
+    ac_status, decide, loop_update, new_battery_info, Backlight, BacklightError, BatteryInfo,
+    BatterySource, PowerSource,
+};
+use battery::State;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/* What the daemon saw on one loop */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sample {
+    // milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    #[serde(
+        serialize_with = "serialize_state",
+        deserialize_with = "deserialize_state"
+    )]
+    pub state: State,
+    // 0.0 to 1.0
+    pub soc: f32,
+    pub ac: bool,
+}
+
+// charging, discharging, full, empty or unknown, like the status reply
+fn serialize_state<S: Serializer>(state: &State, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&state.to_string())
+}
+
+fn deserialize_state<'de, D: Deserializer<'de>>(deserializer: D) -> Result<State, D::Error> {
+    let state = String::deserialize(deserializer)?;
+    state
+        .parse()
+        .map_err(|_| serde::de::Error::custom(format!("unknown battery state `{}`", state)))
+}
+
+/* Appends a Sample to a trace file on every loop of the daemon */
+pub struct Recorder {
+    file: File,
+}
+
+impl Recorder {
+    // a trace that already exists is added to, so restarting the daemon keeps the old samples
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Recorder { file })
+    }
+
+    pub fn record(&mut self, info: &BatteryInfo, now: SystemTime) -> io::Result<()> {
+        let sample = Sample {
+            timestamp_ms: now
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            state: info.new_status,
+            soc: info.soc,
+            ac: info.new_ac_status == '1',
+        };
+
+        let mut line = serde_json::to_string(&sample)?;
+        line.push('\n');
+        // one write per sample, so a crash can't leave half a line behind
+        self.file.write_all(line.as_bytes())
+    }
+}
+
+/* Reads every sample in the trace at path. Blank lines are skipped, and a line that isn't a
+ * sample is an error saying which line it is.
+ * */
+pub fn read_trace(path: &Path) -> io::Result<Vec<Sample>> {
+    let mut samples = Vec::new();
+
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let sample = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", i + 1, e))
+        })?;
+        samples.push(sample);
+    }
+
+    Ok(samples)
+}
+
+/* A brightness change the daemon would have made */
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Decision {
+    // milliseconds since the first sample
+    pub time_ms: u64,
+    #[serde(serialize_with = "serialize_state")]
+    pub state: State,
+    pub soc: f32,
+    pub ac: bool,
+    pub devices: Vec<DeviceDecision>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DeviceDecision {
+    pub name: String,
+    pub brightness: u32,
+}
+
+/* The batteries and AC as the sample the replay is at says */
+#[derive(Clone, Copy)]
+struct Replay<'a> {
+    samples: &'a [Sample],
+    // the index of that sample
+    due: &'a Cell<usize>,
+}
+
+impl Replay<'_> {
+    fn sample(&self) -> Option<&Sample> {
+        self.samples.get(self.due.get())
+    }
+}
+
+impl BatterySource for Replay<'_> {
+    fn scan(&mut self) -> Result<(), battery::Error> {
+        Ok(())
+    }
+
+    fn refresh(&mut self) -> Result<(), battery::Error> {
+        Ok(())
+    }
+
+    // a trace only has what the batteries added up to, so that's one battery
+    fn readings(&self) -> Vec<Reading> {
+        self.sample()
+            .map(|s| Reading {
+                model: None,
+                serial_number: None,
+                state: s.state,
+                energy: s.soc,
+                energy_full: 1.0,
+                state_of_charge: s.soc,
+            })
+            .into_iter()
+            .collect()
+    }
+}
+
+impl PowerSource for Replay<'_> {
+    // a trace doesn't name the supplies
+    fn supplies(&self, _pinned: Option<&str>) -> io::Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn ac_online(&self, _pinned: Option<&str>) -> bool {
+        self.sample().is_some_and(|s| s.ac)
+    }
+}
+
+/* A backlight that only exists in a replay, starting out at its max_brightness */
+struct Replayed {
+    name: String,
+    max_brightness: u32,
+    brightness: Cell<u32>,
+}
+
+impl Backlight for Replayed {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn max_brightness(&self) -> u32 {
+        self.max_brightness
+    }
+
+    fn brightness(&self) -> Result<u32, BacklightError> {
+        Ok(self.brightness.get())
+    }
+
+    fn actual_brightness(&self) -> Result<u32, BacklightError> {
+        self.brightness()
+    }
+
+    fn change_gamma(&self, gamma: u32) -> Result<(), BacklightError> {
+        if gamma > self.max_brightness {
+            return Err(BacklightError::OutOfRange {
+                given: gamma,
+                max: self.max_brightness,
+            });
+        }
+        self.brightness.set(gamma);
+        Ok(())
+    }
+}
+
+/* Replays samples through the daemon's loop, and returns every brightness change it would have
+ * made. devices are the (name, max_brightness) of the backlights to decide for.
+ *
+ * Between samples the daemon waits on a ManualClock, stepping its fades, until the time the next
+ * one was recorded at, and then reads the batteries and decides like it does on every loop.
+ * Nothing is ever changed by hand in a replay, so a decision is the brightness a fade would end at.
+ * */
+pub fn simulate(samples: &[Sample], config: &Config, devices: &[(String, u32)]) -> Vec<Decision> {
+    let start = samples.first().map(|s| s.timestamp_ms).unwrap_or(0);
+    let mut decisions = Vec::new();
+
+    let clock = ManualClock::new();
+    let events = Events::none();
+    let due = Cell::new(0);
+    let replay = Replay { samples, due: &due };
+    // the trace's battery has no model or serial number to select it by
+    let mut batteries = Batteries::new(replay, &BatterySelection::Aggregate);
+    let mut info = new_battery_info(config.clone(), &batteries);
+    let mut faders: Vec<Fader<Replayed>> = devices
+        .iter()
+        .map(|(name, max)| {
+            Fader::new(Replayed {
+                name: name.clone(),
+                max_brightness: *max,
+                brightness: Cell::new(*max),
+            })
+        })
+        .collect();
+    let mut failures = 0;
+
+    for (i, sample) in samples.iter().enumerate() {
+        let at = Duration::from_millis(sample.timestamp_ms.saturating_sub(start));
+        let sleep = at.saturating_sub(clock.elapsed());
+        clock.allow(sleep);
+        due.set(i);
+        // the replay's batteries always read, so this can't fail
+        let _ = loop_update(
+            &clock,
+            &mut info,
+            &mut batteries,
+            &mut faders,
+            &events,
+            sleep,
+            &mut failures,
+        );
+
+        info.new_status = batteries.state();
+        info.new_ac_status = ac_status(&replay, &info.gamma_values, &batteries);
+        let before: Vec<Option<u32>> = faders.iter().map(|f| f.target()).collect();
+        decide(&mut faders, &mut info, clock.now());
+
+        if faders.iter().map(|f| f.target()).ne(before) {
+            decisions.push(Decision {
+                time_ms: clock.elapsed().as_millis() as u64,
+                state: sample.state,
+                soc: sample.soc,
+                ac: sample.ac,
+                devices: faders
+                    .iter()
+                    .filter_map(|f| {
+                        f.target().map(|brightness| DeviceDecision {
+                            name: f.device.name().to_string(),
+                            brightness,
+                        })
+                    })
+                    .collect(),
+            });
+        }
+    }
+
+    decisions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::config::Level;
+    use crate::daemon::policy::Policy;
+
+    fn sample(minutes: u64, state: State, soc: f32, ac: bool) -> Sample {
+        Sample {
+            timestamp_ms: 1_760_000_000_000 + minutes * 60_000,
+            state,
+            soc,
+            ac,
+        }
+    }
+
+    #[test]
+    fn test_trace_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("gamma_daemon_trace_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut info = BatteryInfo {
+            soc: 0.8,
+            policy: Policy::default(),
+            new_status: State::Discharging,
+            new_ac_status: '0',
+            gamma_values: Box::default(),
+        };
+        let now = UNIX_EPOCH + Duration::from_millis(1_760_000_000_000);
+        Recorder::create(&path).unwrap().record(&info, now).unwrap();
+        // a second daemon adds to the same trace
+        info.new_status = State::Charging;
+        info.new_ac_status = '1';
+        Recorder::create(&path)
+            .unwrap()
+            .record(&info, now + Duration::from_secs(60))
+            .unwrap();
+
+        assert_eq!(
+            read_trace(&path).unwrap(),
+            vec![
+                sample(0, State::Discharging, 0.8, false),
+                sample(1, State::Charging, 0.8, true),
+            ]
+        );
+
+        std::fs::write(
+            &path,
+            "{\"timestamp_ms\":0,\"state\":\"full\",\"soc\":1.0,\"ac\":true}\n\n{\"timestamp_ms\":1,\"state\":\"dead\",\"soc\":1.0,\"ac\":true}\n",
+        )
+        .unwrap();
+        let e = read_trace(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e
+            .to_string()
+            .starts_with("line 3: unknown battery state `dead`"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_simulate() {
+        let config = Config {
+            full: Level::Raw(250),
+            low: Level::Raw(50),
+            low_perc: 20,
+            charging: Level::Raw(200),
+            discharging: Level::Raw(150),
+            ..Default::default()
+        };
+        let devices = [("intel_backlight".to_string(), 255)];
+        let samples = [
+            sample(0, State::Discharging, 0.5, false),
+            // nothing changed, so nothing is decided
+            sample(1, State::Discharging, 0.4, false),
+            sample(30, State::Discharging, 0.19, false),
+            sample(31, State::Discharging, 0.18, false),
+            sample(35, State::Charging, 0.18, true),
+            sample(120, State::Full, 1.0, true),
+        ];
+
+        let decided: Vec<(u64, u32)> = simulate(&samples, &config, &devices)
+            .iter()
+            .map(|d| (d.time_ms / 60_000, d.devices[0].brightness))
+            .collect();
+        assert_eq!(decided, vec![(0, 150), (30, 50), (35, 200), (120, 250)]);
+
+        // like the daemon, the charge reading a little higher again doesn't leave low, and a
+        // machine that only knows it is plugged in uses ac_in
+        let samples = [
+            sample(0, State::Discharging, 0.19, false),
+            sample(5, State::Discharging, 0.21, false),
+            sample(10, State::Unknown, 1.0, true),
+        ];
+        let decided: Vec<(u64, u32)> = simulate(&samples, &config, &devices)
+            .iter()
+            .map(|d| (d.time_ms / 60_000, d.devices[0].brightness))
+            .collect();
+        assert_eq!(decided, vec![(0, 50), (10, 225)]);
+    }
+
+    #[test]
+    fn test_simulate_curve() {
+        let config: Config = toml::from_str("[curve]\n100 = 200\n0 = 0\n").unwrap();
+        let devices = [("intel_backlight".to_string(), 255)];
+        let samples = [
+            sample(0, State::Discharging, 0.5, false),
+            sample(10, State::Discharging, 0.5, false),
+            sample(20, State::Discharging, 0.25, false),
+        ];
+
+        let decided: Vec<u32> = simulate(&samples, &config, &devices)
+            .iter()
+            .map(|d| d.devices[0].brightness)
+            .collect();
+        assert_eq!(decided, vec![100, 50]);
+    }
+}
@@ -0,0 +1,206 @@
+/*  Contains a listener for kernel uevents.
+ *
+ *  The kernel broadcasts a uevent on a netlink socket whenever a device changes, i.e: when the AC
+ *  adapter is plugged in, a battery changes state, or a backlight device shows up. Listening for
+ *  those lets the daemon sleep until something actually happens, instead of waking every second to
+ *  check, and react within milliseconds of the charger being plugged or unplugged.
+ *
+ * */
+
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+// the only subsystems we wake up for
+const SUBSYSTEMS: [&str; 2] = ["power_supply", "backlight"];
+
+// multicast group the kernel sends uevents to (udev rebroadcasts them on group 2)
+const KERNEL_GROUP: u32 = 1;
+
+/* A uevent from the kernel */
+#[derive(Debug, PartialEq, Eq)]
+pub struct Uevent {
+    pub action: String,
+    pub devpath: String,
+    pub subsystem: String,
+}
+
+impl Uevent {
+    /* True if the daemon has to look at the devices again: a power_supply changed, or a backlight
+     * came or went. A backlight also sends a change for every write to its brightness, which
+     * includes each step of our own fades, so those don't count.
+     * */
+    pub fn wakes(&self) -> bool {
+        match self.subsystem.as_str() {
+            "power_supply" => true,
+            "backlight" => self.action == "add" || self.action == "remove",
+            _ => false,
+        }
+    }
+}
+
+/* Parses a kernel uevent message.
+ *
+ * Messages look like "change@/devices/.../AC\0ACTION=change\0DEVPATH=...\0SUBSYSTEM=power_supply\0...".
+ * Returns None if the message isn't a kernel uevent.
+ * */
+pub fn parse_uevent(msg: &[u8]) -> Option<Uevent> {
+    let mut fields = msg.split(|b| *b == 0).map(String::from_utf8_lossy);
+
+    let header = fields.next()?;
+    let (action, devpath) = header.split_once('@')?;
+
+    let subsystem = fields
+        .find_map(|field| field.strip_prefix("SUBSYSTEM=").map(str::to_string))
+        .unwrap_or_default();
+
+    Some(Uevent {
+        action: action.to_string(),
+        devpath: devpath.to_string(),
+        subsystem,
+    })
+}
+
+/* A netlink socket subscribed to kernel uevents */
+pub struct UeventSocket {
+    fd: RawFd,
+}
+
+impl UeventSocket {
+    pub fn open() -> io::Result<Self> {
+        // SAFETY: plain socket syscalls, the fd is owned by the returned UeventSocket
+        unsafe {
+            let fd = libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                libc::NETLINK_KOBJECT_UEVENT,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut addr: libc::sockaddr_nl = mem::zeroed();
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            addr.nl_groups = KERNEL_GROUP;
+
+            let bound = libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            );
+            if bound < 0 {
+                let e = io::Error::last_os_error();
+                libc::close(fd);
+                return Err(e);
+            }
+
+            Ok(UeventSocket { fd })
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /* Reads every message waiting on the socket, and returns the power_supply and backlight ones.
+     *
+     * If the socket overflowed, the messages we missed may have included the AC being plugged in,
+     * so a power_supply change is returned in their place.
+     * */
+    pub fn read(&self) -> io::Result<Vec<Uevent>> {
+        let mut events = Vec::new();
+        let mut buf = [0u8; 8192];
+
+        loop {
+            // SAFETY: buf is valid for buf.len() bytes
+            let len =
+                unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if len < 0 {
+                let e = io::Error::last_os_error();
+                if e.raw_os_error() == Some(libc::ENOBUFS) {
+                    events.push(missed());
+                    continue;
+                }
+                return match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(events),
+                    _ => Err(e),
+                };
+            }
+
+            if let Some(event) = parse_uevent(&buf[..len as usize]) {
+                if SUBSYSTEMS.contains(&event.subsystem.as_str()) {
+                    events.push(event);
+                }
+            }
+        }
+    }
+}
+
+// stands in for the uevents lost when the socket overflows
+fn missed() -> Uevent {
+    Uevent {
+        action: "change".to_string(),
+        devpath: String::new(),
+        subsystem: "power_supply".to_string(),
+    }
+}
+
+impl Drop for UeventSocket {
+    fn drop(&mut self) {
+        // SAFETY: we own the fd
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_uevent() {
+        let msg = b"change@/devices/LNXSYSTM:00/LNXSYBUS:00/ACPI0003:00/power_supply/AC\0ACTION=change\0DEVPATH=/devices/LNXSYSTM:00/LNXSYBUS:00/ACPI0003:00/power_supply/AC\0SUBSYSTEM=power_supply\0POWER_SUPPLY_NAME=AC\0POWER_SUPPLY_ONLINE=1\0SEQNUM=4242\0";
+
+        assert_eq!(
+            parse_uevent(msg),
+            Some(Uevent {
+                action: "change".to_string(),
+                devpath: "/devices/LNXSYSTM:00/LNXSYBUS:00/ACPI0003:00/power_supply/AC".to_string(),
+                subsystem: "power_supply".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_backlight_uevent() {
+        let msg = b"add@/devices/pci0000:00/0000:00:02.0/drm/card0/card0-eDP-1/intel_backlight\0ACTION=add\0SUBSYSTEM=backlight\0";
+
+        let event = parse_uevent(msg).unwrap();
+        assert_eq!(event.action, "add");
+        assert_eq!(event.subsystem, "backlight");
+    }
+
+    #[test]
+    fn test_wakes() {
+        let uevent = |action: &str, subsystem: &str| Uevent {
+            action: action.to_string(),
+            devpath: "/devices/test".to_string(),
+            subsystem: subsystem.to_string(),
+        };
+
+        assert!(uevent("change", "power_supply").wakes());
+        assert!(uevent("add", "backlight").wakes());
+        assert!(uevent("remove", "backlight").wakes());
+        // every step of a fade sends one of these
+        assert!(!uevent("change", "backlight").wakes());
+        assert!(missed().wakes());
+    }
+
+    #[test]
+    fn test_parse_non_kernel_message() {
+        // udev's own messages start with a binary header, not action@devpath
+        assert_eq!(parse_uevent(b"libudev\0\xfe\xed\xca\xfe"), None);
+        assert_eq!(parse_uevent(b""), None);
+    }
+}
@@ -0,0 +1,120 @@
+/*  Contains the Error type for everything that can stop gamma_daemon, and the exit code each one
+ *  ends the process with.
+ *
+ *  Exit codes follow sysexits.h, so scripts and systemd can tell a broken config apart from
+ *  missing hardware.
+ *
+ * */
+
+use crate::daemon::config::ConfigError;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+// the command line didn't make sense
+pub const EXIT_USAGE: i32 = 64;
+// a backlight, or the running daemon, isn't there
+pub const EXIT_UNAVAILABLE: i32 = 69;
+// forking or another system call failed
+pub const EXIT_OSERR: i32 = 71;
+// reading the batteries or power supplies failed
+pub const EXIT_IOERR: i32 = 74;
+// the config file is broken
+pub const EXIT_CONFIG: i32 = 78;
+
+#[derive(Debug)]
+pub enum Error {
+    // finding, reading or changing a backlight device
+    Backlight {
+        context: String,
+        source: crate::daemon::BacklightError,
+    },
+    // there are no backlight devices at all in the directory
+    NoBacklights(PathBuf),
+    // reading the batteries
+    Battery(battery::Error),
+    // reading /sys/class/power_supply
+    PowerSupply(io::Error),
+    // reading the config file
+    Config {
+        path: String,
+        source: ConfigError,
+    },
+    // talking over the control socket
+    Ipc(io::Error),
+    // forking into the background
+    Daemonize(String),
+    // reading or writing a trace, see daemon::trace
+    Trace {
+        context: String,
+        source: io::Error,
+    },
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Backlight { .. } | Error::NoBacklights(_) | Error::Ipc(_) => EXIT_UNAVAILABLE,
+            Error::Battery(_) | Error::PowerSupply(_) | Error::Trace { .. } => EXIT_IOERR,
+            Error::Config { .. } => EXIT_CONFIG,
+            Error::Daemonize(_) => EXIT_OSERR,
+        }
+    }
+
+    /* Returns true for errors that can go away on their own, i.e: a battery briefly disappearing
+     * while the notebook resumes from suspend. These are worth retrying before giving up.
+     * */
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Battery(_) | Error::PowerSupply(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Backlight { context, source } => write!(f, "{}: {}", context, source),
+            Error::NoBacklights(dir) => {
+                write!(f, "No backlight devices found in {}", dir.display())
+            }
+            Error::Battery(e) => write!(f, "Can't read the batteries: {}", e),
+            Error::PowerSupply(e) => write!(f, "Can't read the power supplies: {}", e),
+            Error::Config { path, source } => write!(f, "Error in {}: {}", path, source),
+            Error::Ipc(e) => write!(f, "Can't reach gamma_daemon, is it running? ({})", e),
+            Error::Daemonize(e) => write!(f, "Can't start in the background: {}", e),
+            Error::Trace { context, source } => write!(f, "{}: {}", context, source),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<battery::Error> for Error {
+    fn from(e: battery::Error) -> Self {
+        Error::Battery(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        let config = Error::Config {
+            path: "conf.toml".to_string(),
+            source: ConfigError::Io(io::Error::from(io::ErrorKind::NotFound)),
+        };
+        assert_eq!(config.exit_code(), EXIT_CONFIG);
+        assert!(!config.is_transient());
+
+        let battery = Error::Battery(battery::Error::from(io::Error::from(
+            io::ErrorKind::NotFound,
+        )));
+        assert_eq!(battery.exit_code(), EXIT_IOERR);
+        assert!(battery.is_transient());
+
+        let none = Error::NoBacklights(PathBuf::from("/sys/class/backlight"));
+        assert_eq!(none.exit_code(), EXIT_UNAVAILABLE);
+        assert!(!none.is_transient());
+    }
+}
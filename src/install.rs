@@ -0,0 +1,235 @@
+/*  Contains the install subcommand, which generates the files needed to run GammaDaemon as a
+ *  service: a udev rules file giving the daemon's group write access to the backlights, a systemd
+ *  user unit, and a systemd system unit.
+ *
+ *  Without a prefix the files are printed, so they can be checked or copied by hand. With one,
+ *  they are written under it, i.e: `gamma_daemon install /` writes them where systemd and udev
+ *  look for them.
+ *
+ * */
+
+use crate::daemon;
+use crate::daemon::sysfs::Sysfs;
+use crate::daemon::Backlight;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const RULES_PATH: &str = "etc/udev/rules.d/90-gamma_daemon.rules";
+const SYSTEM_UNIT_PATH: &str = "etc/systemd/system/gamma_daemon.service";
+const USER_UNIT_PATH: &str = "etc/systemd/user/gamma_daemon.service";
+
+// used when we can't tell where we were run from
+const DEFAULT_EXE: &str = "/usr/bin/gamma_daemon";
+
+/* A file to install, path is relative to the prefix */
+#[derive(Debug, PartialEq)]
+pub struct InstallFile {
+    pub path: &'static str,
+    pub contents: String,
+}
+
+/* Returns the udev rules for devices, one rule per device so only the backlights we found are
+ * opened up. If no devices were found, the rule matches every backlight instead.
+ * */
+pub fn udev_rules(devices: &[String], group: &str) -> String {
+    let mut rules = String::from(
+        "# Generated by gamma_daemon install\n# Lets the group gamma_daemon runs as change the backlight brightness\n",
+    );
+    let rule = |kernel: &str| {
+        format!(
+            "ACTION==\"add\", SUBSYSTEM==\"backlight\", {}RUN+=\"/bin/chgrp {} /sys/class/backlight/%k/brightness\", RUN+=\"/bin/chmod g+w /sys/class/backlight/%k/brightness\"\n",
+            kernel, group
+        )
+    };
+
+    match devices.is_empty() {
+        true => rules += &rule(""),
+        false => {
+            for device in devices {
+                rules += &rule(&format!("KERNEL==\"{}\", ", device));
+            }
+        }
+    }
+
+    rules
+}
+
+/* Returns the systemd unit that runs the daemon for one user, started with their session.
+ * The user needs to be in group to change the brightness.
+ * */
+pub fn user_unit(exe: &Path) -> String {
+    format!(
+        "[Unit]
+Description=Changes screen brightness based on notebook battery life
+
+[Service]
+Type=notify
+ExecStart={} run --foreground
+Restart=on-failure
+WatchdogSec=60
+
+[Install]
+WantedBy=default.target
+",
+        exe.display()
+    )
+}
+
+/* Returns the systemd unit that runs the daemon for the whole machine, as a throwaway user in group.
+ * It has no home of its own, so it reads its config from config::SYSTEM_CONFIG, and puts its
+ * control socket in /run/gamma_daemon.
+ * */
+pub fn system_unit(exe: &Path, group: &str) -> String {
+    format!(
+        "[Unit]
+Description=Changes screen brightness based on notebook battery life
+After=systemd-udevd.service
+
+[Service]
+Type=notify
+ExecStart={} run --foreground
+DynamicUser=yes
+SupplementaryGroups={}
+RuntimeDirectory=gamma_daemon
+Environment=XDG_RUNTIME_DIR=%t/gamma_daemon
+Restart=on-failure
+WatchdogSec=60
+
+[Install]
+WantedBy=multi-user.target
+",
+        exe.display(),
+        group
+    )
+}
+
+pub fn files(devices: &[String], exe: &Path, group: &str) -> Vec<InstallFile> {
+    vec![
+        InstallFile {
+            path: RULES_PATH,
+            contents: udev_rules(devices, group),
+        },
+        InstallFile {
+            path: USER_UNIT_PATH,
+            contents: user_unit(exe),
+        },
+        InstallFile {
+            path: SYSTEM_UNIT_PATH,
+            contents: system_unit(exe, group),
+        },
+    ]
+}
+
+// writes each file under prefix, making directories as needed
+fn write_files(files: &[InstallFile], prefix: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut written = Vec::new();
+
+    for file in files {
+        let path = prefix.join(file.path);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, &file.contents)?;
+        written.push(path);
+    }
+
+    Ok(written)
+}
+
+/* Prints the files for the backlights on this machine, or writes them under prefix.
+ * Returns the exit code.
+ * */
+pub fn install(prefix: Option<String>, sysfs: &Sysfs) -> i32 {
+    let devices: Vec<String> = match sysfs.backlights() {
+        Ok(devices) => devices.iter().map(|d| d.name().to_string()).collect(),
+        Err(e) => {
+            eprintln!(
+                "Can't read the backlight devices ({}), the udev rules will match every backlight",
+                e
+            );
+            Vec::new()
+        }
+    };
+    let exe = env::current_exe().unwrap_or_else(|_| PathBuf::from(DEFAULT_EXE));
+    let files = files(&devices, &exe, daemon::GROUP);
+
+    let prefix = match prefix {
+        Some(prefix) => prefix,
+        None => {
+            for file in &files {
+                println!("# /{}\n{}", file.path, file.contents);
+            }
+            return 0;
+        }
+    };
+
+    match write_files(&files, Path::new(&prefix)) {
+        Ok(written) => {
+            for path in written {
+                println!("Wrote {}", path.display());
+            }
+            println!(
+                "Reload udev with `udevadm control --reload && udevadm trigger -s backlight`, and systemd with `systemctl daemon-reload`"
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Can't write the files under {}: {}", prefix, e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udev_rules() {
+        let devices = vec!["intel_backlight".to_string(), "acpi_video0".to_string()];
+        let rules = udev_rules(&devices, "video");
+
+        assert!(rules.contains("KERNEL==\"intel_backlight\", RUN+=\"/bin/chgrp video /sys/class/backlight/%k/brightness\""));
+        assert!(rules.contains("KERNEL==\"acpi_video0\""));
+        assert_eq!(rules.lines().filter(|l| l.starts_with("ACTION")).count(), 2);
+
+        // nothing found, so open up every backlight
+        let rules = udev_rules(&[], "video");
+        assert_eq!(rules.lines().filter(|l| l.starts_with("ACTION")).count(), 1);
+        assert!(!rules.contains("KERNEL"));
+    }
+
+    #[test]
+    fn test_units() {
+        let exe = Path::new("/opt/gamma_daemon");
+
+        let user = user_unit(exe);
+        assert!(user.contains("ExecStart=/opt/gamma_daemon run --foreground\n"));
+        assert!(user.contains("WantedBy=default.target"));
+
+        let system = system_unit(exe, "video");
+        assert!(system.contains("ExecStart=/opt/gamma_daemon run --foreground\n"));
+        assert!(system.contains("SupplementaryGroups=video\n"));
+        assert!(system.contains("WantedBy=multi-user.target"));
+    }
+
+    #[test]
+    fn test_write_files() {
+        let prefix =
+            std::env::temp_dir().join(format!("gamma_daemon_install_{}", std::process::id()));
+        let files = files(&[], Path::new(DEFAULT_EXE), "video");
+
+        let written = write_files(&files, &prefix).unwrap();
+        assert_eq!(written.len(), 3);
+        assert_eq!(
+            fs::read_to_string(prefix.join(RULES_PATH)).unwrap(),
+            udev_rules(&[], "video")
+        );
+        assert!(prefix.join(SYSTEM_UNIT_PATH).exists());
+        assert!(prefix.join(USER_UNIT_PATH).exists());
+
+        fs::remove_dir_all(prefix).unwrap();
+    }
+}
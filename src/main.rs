@@ -1,18 +1,92 @@
+mod cli;
 mod daemon;
-use bulbb::monitor::MonitorDevice;
+mod error;
+mod install;
+use cli::Command;
+use daemon::batteries::CrateBatteries;
+use daemon::clock::SystemClock;
+use daemon::sysfs::{Sysfs, SysfsBatteries};
+use error::Error;
 use std::env;
+use std::path::PathBuf;
+use std::process;
 
 fn main() {
-    //find monitor device
-    let monitors = MonitorDevice::get_all_monitor_devices().unwrap();
-    
-    let mut args: Vec<String> = env::args().collect();
-    //get a ref to the first monitor in the list of monitors
-    let main_monitor: &MonitorDevice = &monitors[0];
-    
-    if args.len() == 1 {
-        args.push("NAN".to_string());
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let cli = match cli::parse_args(&args) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("gamma_daemon: {}\n\n{}", e, cli::USAGE);
+            process::exit(error::EXIT_USAGE);
+        }
+    };
+
+    let sysfs = Sysfs::from_args(cli.sysfs_root);
+    let code = match cli.command {
+        Command::Run {
+            config,
+            foreground,
+            record,
+        } => match run(config, foreground, record, sysfs) {
+            Ok(_) => 0,
+            Err(e) => {
+                eprintln!("gamma_daemon: {}", e);
+                e.exit_code()
+            }
+        },
+        Command::Send(request) => cli::send(request, cli.json),
+        Command::CheckConfig { config } => cli::check_config(config, &sysfs, cli.json),
+        Command::PrintConfig { config, defaults } => cli::print_config(config, defaults, cli.json),
+        Command::Simulate { trace, config } => cli::simulate(&trace, config, &sysfs, cli.json),
+        Command::Install { prefix } => install::install(prefix, &sysfs),
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            0
+        }
+    };
+    process::exit(code);
+}
+
+fn run(
+    config: Option<String>,
+    foreground: bool,
+    record: Option<String>,
+    sysfs: Sysfs,
+) -> Result<(), Error> {
+    //find every monitor device, the daemon drives all of them
+    let monitors = sysfs.backlights().map_err(|source| Error::Backlight {
+        context: "Can't read the backlight devices".to_string(),
+        source,
+    })?;
+
+    if monitors.is_empty() {
+        return Err(Error::NoBacklights(sysfs.backlight_dir()));
+    }
+
+    if foreground {
+        daemon::log_to_stderr();
+    }
+    let options = daemon::RunOptions {
+        config_paths: daemon::config::search_paths(config.as_deref()),
+        config: config.as_ref().map(PathBuf::from),
+        foreground,
+        control_socket: daemon::control::socket_path(),
+        record: record.map(PathBuf::from),
+    };
+    //start the daemon. The battery crate only reads the real /sys, and fails without a
+    //power_supply class (as in some containers), so anything else is read by our own reader.
+    match sysfs.is_default() && sysfs.power_supply_dir().is_dir() {
+        true => daemon::run(
+            &monitors,
+            CrateBatteries::new()?,
+            sysfs,
+            SystemClock,
+            &options,
+        ),
+        false => {
+            let batteries = SysfsBatteries::new(&sysfs).map_err(Error::PowerSupply)?;
+            daemon::run(&monitors, batteries, sysfs, SystemClock, &options)
+        }
     }
-    //start the daemon
-    daemon::run(main_monitor, &args[1]).unwrap();
 }